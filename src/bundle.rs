@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+
+use ethers::{
+    abi::{AbiDecode, AbiError},
+    contract::EthCall,
+    providers::Middleware,
+    types::{Address, Bytes, Transaction, H256},
+};
+use tracing::debug;
+
+use crate::{
+    constrant::EntryPointSpec,
+    trace::{calls_to, trace_call_tree},
+    uo::{
+        EntryPointVersion, HandleAggregatedOpsCall, HandleAggregatedOpsV07Call, HandleOpsCall,
+        HandleOpsV07Call, PackedUserOperation, UserOperation,
+    },
+};
+
+/// A user operation taken out of a bundle transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BundledOperation {
+    pub uo: UserOperation,
+    pub uo_hash: H256,
+    pub aggregator: Option<Address>,
+    pub aggregated_signature: Option<Bytes>,
+}
+
+/// User operation layouts accepted by the different EntryPoint versions.
+trait EntryPointOperation: Into<UserOperation> {
+    fn uo_hash(&self, entry_point_addr: Address, chain_id: u64) -> H256;
+}
+
+impl EntryPointOperation for UserOperation {
+    fn uo_hash(&self, entry_point_addr: Address, chain_id: u64) -> H256 {
+        UserOperation::uo_hash(self, entry_point_addr, chain_id)
+    }
+}
+
+impl EntryPointOperation for PackedUserOperation {
+    fn uo_hash(&self, entry_point_addr: Address, chain_id: u64) -> H256 {
+        PackedUserOperation::uo_hash(self, entry_point_addr, chain_id)
+    }
+}
+
+/// Operations of one bundle group. The EntryPoint runs a group with the zero aggregator like
+/// plain `handleOps`, so it gets neither an aggregator nor an aggregated signature.
+fn bundled<T: EntryPointOperation>(
+    ops: Vec<T>,
+    aggregator: Option<(Address, Bytes)>,
+    entry_point: Address,
+    chain_id: u64,
+) -> impl Iterator<Item = BundledOperation> {
+    let aggregator = aggregator.filter(|(address, _)| !address.is_zero());
+    ops.into_iter().map(move |uo| BundledOperation {
+        uo_hash: uo.uo_hash(entry_point, chain_id),
+        uo: uo.into(),
+        aggregator: aggregator.as_ref().map(|(address, _)| *address),
+        aggregated_signature: aggregator.as_ref().map(|(_, signature)| signature.clone()),
+    })
+}
+
+/// Decode the `handleOps` or `handleAggregatedOps` calldata of `version` and hash every
+/// operation against `entry_point`. Aggregated groups are flattened in bundle order.
+pub fn decode_bundle(
+    input: &[u8],
+    version: EntryPointVersion,
+    entry_point: Address,
+    chain_id: u64,
+) -> Result<Vec<BundledOperation>, AbiError> {
+    let selector = input.get(..4).unwrap_or_default();
+    let ops = match version {
+        EntryPointVersion::V0_6 if selector == HandleAggregatedOpsCall::selector() => {
+            HandleAggregatedOpsCall::decode(input)?
+                .ops_per_aggregator
+                .into_iter()
+                .flat_map(|group| {
+                    bundled(
+                        group.user_ops,
+                        Some((group.aggregator, group.signature)),
+                        entry_point,
+                        chain_id,
+                    )
+                })
+                .collect()
+        }
+        EntryPointVersion::V0_6 => bundled(
+            HandleOpsCall::decode(input)?.ops,
+            None,
+            entry_point,
+            chain_id,
+        )
+        .collect(),
+        EntryPointVersion::V0_7 if selector == HandleAggregatedOpsV07Call::selector() => {
+            HandleAggregatedOpsV07Call::decode(input)?
+                .ops_per_aggregator
+                .into_iter()
+                .flat_map(|group| {
+                    bundled(
+                        group.user_ops,
+                        Some((group.aggregator, group.signature)),
+                        entry_point,
+                        chain_id,
+                    )
+                })
+                .collect()
+        }
+        EntryPointVersion::V0_7 => bundled(
+            HandleOpsV07Call::decode(input)?.ops,
+            None,
+            entry_point,
+            chain_id,
+        )
+        .collect(),
+    };
+    Ok(ops)
+}
+
+/// Look for bundle calls embedded anywhere in `input`, e.g. inside a multicall or a Safe
+/// `execTransaction`, and return every operation they carry. ABI decoding ignores trailing
+/// bytes, so decoding from every selector occurrence is enough.
+pub fn scan_calldata(
+    input: &[u8],
+    entry_point: &EntryPointSpec,
+    chain_id: u64,
+) -> Vec<BundledOperation> {
+    let selectors: [[u8; 4]; 2] = match entry_point.version {
+        EntryPointVersion::V0_6 => [
+            HandleOpsCall::selector(),
+            HandleAggregatedOpsCall::selector(),
+        ],
+        EntryPointVersion::V0_7 => [
+            HandleOpsV07Call::selector(),
+            HandleAggregatedOpsV07Call::selector(),
+        ],
+    };
+    input
+        .windows(4)
+        .enumerate()
+        .filter(|(_, window)| selectors.iter().any(|selector| selector == window))
+        .filter_map(|(offset, _)| {
+            decode_bundle(
+                &input[offset..],
+                entry_point.version,
+                entry_point.address,
+                chain_id,
+            )
+            .ok()
+        })
+        .flatten()
+        .collect()
+}
+
+/// Keep the operations of `ops` listed in `uo_hashes`. Returns true once all of them are found.
+fn collect_wanted(
+    found: &mut HashMap<H256, BundledOperation>,
+    ops: Vec<BundledOperation>,
+    uo_hashes: &[H256],
+) -> bool {
+    for op in ops {
+        if uo_hashes.contains(&op.uo_hash) {
+            found.entry(op.uo_hash).or_insert(op);
+        }
+    }
+    uo_hashes.iter().all(|hash| found.contains_key(hash))
+}
+
+/// Find the user operations `uo_hashes` in `transaction`, decoding the bundle only once for all
+/// of them. Operations that can't be found are missing from the returned map.
+///
+/// Transactions sent straight to the EntryPoint are decoded directly. Bundles going through a
+/// multicall, a Safe module or a proxy are found by scanning the nested calldata and, as a last
+/// resort, by walking the `debug_traceTransaction` call tree.
+pub async fn find_bundled_operations<M: Middleware>(
+    provider: &M,
+    transaction: &Transaction,
+    entry_point: &EntryPointSpec,
+    chain_id: u64,
+    uo_hashes: &[H256],
+) -> anyhow::Result<HashMap<H256, BundledOperation>>
+where
+    M::Error: 'static,
+{
+    let mut found = HashMap::with_capacity(uo_hashes.len());
+    if transaction.to == Some(entry_point.address) {
+        if let Ok(ops) = decode_bundle(
+            &transaction.input,
+            entry_point.version,
+            entry_point.address,
+            chain_id,
+        ) {
+            if collect_wanted(&mut found, ops, uo_hashes) {
+                return Ok(found);
+            }
+        }
+    }
+    let ops = scan_calldata(&transaction.input, entry_point, chain_id);
+    if collect_wanted(&mut found, ops, uo_hashes) {
+        return Ok(found);
+    }
+
+    debug!(
+        "Tracing transaction {:?} to find {} user operations",
+        transaction.hash,
+        uo_hashes.len() - found.len()
+    );
+    let frame = trace_call_tree(provider, transaction.hash).await?;
+    for input in calls_to(&frame, entry_point.address) {
+        if let Ok(ops) = decode_bundle(&input, entry_point.version, entry_point.address, chain_id) {
+            if collect_wanted(&mut found, ops, uo_hashes) {
+                break;
+            }
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use ethers::{
+        abi::{encode, AbiEncode, Token},
+        providers::{Http, Provider},
+        types::{Address, Bytes, Transaction, H256, U256},
+    };
+    use serde_json::Value;
+
+    use super::{decode_bundle, find_bundled_operations, scan_calldata};
+    use crate::{
+        constrant::{EntryPointSpec, ENTRY_POINT_ADDR},
+        test_utils::mock_rpc,
+        uo::{
+            EntryPointVersion, HandleAggregatedOpsCall, HandleOpsCall, UserOperation,
+            UserOpsPerAggregator,
+        },
+    };
+
+    fn entry_point() -> EntryPointSpec {
+        EntryPointSpec {
+            address: Address::from_str(ENTRY_POINT_ADDR).unwrap(),
+            version: EntryPointVersion::V0_6,
+            deployed_block_number: 0,
+        }
+    }
+
+    fn user_operation(nonce: u64) -> UserOperation {
+        UserOperation {
+            sender: Address::from_low_u64_be(1),
+            nonce: U256::from(nonce),
+            init_code: Bytes::new(),
+            call_data: Bytes::new(),
+            call_gas_limit: U256::from(0),
+            verification_gas_limit: U256::from(150000),
+            pre_verification_gas: U256::from(21000),
+            max_fee_per_gas: U256::from(0),
+            max_priority_fee_per_gas: U256::from(1000000000),
+            paymaster_and_data: Bytes::new(),
+            signature: Bytes::new(),
+        }
+    }
+
+    #[test]
+    fn decode_handle_ops() {
+        let entry_point = Address::from_low_u64_be(2);
+        let input = HandleOpsCall {
+            ops: vec![user_operation(0), user_operation(1)],
+            beneficiary: Address::zero(),
+        }
+        .encode();
+        let ops = decode_bundle(&input, EntryPointVersion::V0_6, entry_point, 1).unwrap();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[1].uo_hash, user_operation(1).uo_hash(entry_point, 1));
+        assert_eq!(ops[1].aggregator, None);
+    }
+
+    #[test]
+    fn decode_handle_aggregated_ops() {
+        let entry_point = Address::from_low_u64_be(2);
+        let aggregator = Address::from_low_u64_be(3);
+        let input = HandleAggregatedOpsCall {
+            ops_per_aggregator: vec![
+                UserOpsPerAggregator {
+                    user_ops: vec![user_operation(0)],
+                    aggregator,
+                    signature: Bytes::from(vec![1, 2, 3]),
+                },
+                UserOpsPerAggregator {
+                    user_ops: vec![user_operation(1), user_operation(2)],
+                    aggregator: Address::zero(),
+                    signature: Bytes::new(),
+                },
+            ],
+            beneficiary: Address::zero(),
+        }
+        .encode();
+        let ops = decode_bundle(&input, EntryPointVersion::V0_6, entry_point, 1).unwrap();
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[0].aggregator, Some(aggregator));
+        assert_eq!(
+            ops[0].aggregated_signature,
+            Some(Bytes::from(vec![1, 2, 3]))
+        );
+        assert_eq!(ops[2].uo_hash, user_operation(2).uo_hash(entry_point, 1));
+        assert_eq!(ops[2].aggregator, None);
+        assert_eq!(ops[2].aggregated_signature, None);
+    }
+
+    #[test]
+    fn scan_nested_calldata() {
+        let entry_point = entry_point();
+        let handle_ops = HandleOpsCall {
+            ops: vec![user_operation(0), user_operation(1)],
+            beneficiary: Address::zero(),
+        }
+        .encode();
+        // multicall((address,bytes)[]) wrapping the bundle
+        let mut input = vec![0x82, 0xad, 0x56, 0xcb];
+        input.extend(encode(&[Token::Array(vec![Token::Tuple(vec![
+            Token::Address(entry_point.address),
+            Token::Bytes(handle_ops),
+        ])])]));
+
+        let uo_hash = user_operation(1).uo_hash(entry_point.address, 1);
+        let ops = scan_calldata(&input, &entry_point, 1);
+        assert_eq!(ops.len(), 2);
+        let op = ops.iter().find(|op| op.uo_hash == uo_hash).unwrap();
+        assert_eq!(op.uo, user_operation(1));
+        assert!(!scan_calldata(&input, &entry_point, 5)
+            .iter()
+            .any(|op| op.uo_hash == uo_hash));
+    }
+
+    /// The fixture is not recorded from a chain, there is no transaction hash to point at. It
+    /// follows the geth callTracer output of a router whose `execute(bytes)` decompresses the
+    /// bundle and calls `handleOps`, so the operations only show up in the trace. See
+    /// `tests/fixtures/README.md` to record a real one.
+    #[tokio::test]
+    async fn find_operation_in_trace() {
+        let fixture: Value = serde_json::from_str(include_str!(
+            "../tests/fixtures/trace_nested_handle_ops.json"
+        ))
+        .unwrap();
+        let trace = fixture["result"].clone();
+        let outer_input = Bytes::from_str(trace["input"].as_str().unwrap()).unwrap();
+        let router = Address::from_str(trace["to"].as_str().unwrap()).unwrap();
+        let url = mock_rpc(move |method, _| match method {
+            "debug_traceTransaction" => Ok(trace.clone()),
+            method => Err(format!("unexpected method {method}")),
+        })
+        .await;
+        let provider = Provider::<Http>::try_from(url).unwrap();
+
+        let transaction = Transaction {
+            hash: H256::from_low_u64_be(1),
+            to: Some(router),
+            input: outer_input,
+            ..Default::default()
+        };
+        let uo_hash =
+            H256::from_str("0x5483ef9c93a75f60f98b653a33e1be0589875c3a975296db5e556a2b5fb8fc09")
+                .unwrap();
+        let op = find_bundled_operations(&provider, &transaction, &entry_point(), 1, &[uo_hash])
+            .await
+            .unwrap()
+            .remove(&uo_hash)
+            .unwrap();
+        assert_eq!(op.uo_hash, uo_hash);
+        assert_eq!(op.uo.nonce, U256::from(7));
+        assert_eq!(
+            op.uo.sender,
+            Address::from_str("0x8c5Bc1E2A6B2c3bB5B2A9D1f0e4a7C3d2E1f0A9b").unwrap()
+        );
+    }
+}
@@ -1,12 +1,35 @@
+use std::str::FromStr;
+
+use ethers::types::Address;
 use lazy_static::lazy_static;
 
+use crate::uo::EntryPointVersion;
+
 pub const ENTRY_POINT_ADDR: &str = "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789";
+pub const ENTRY_POINT_V07_ADDR: &str = "0x0000000071727De22E5E9d8BAf0edAc6f37da032";
+
+#[derive(Debug, Clone)]
+pub struct EntryPointSpec {
+    pub address: Address,
+    pub version: EntryPointVersion,
+    pub deployed_block_number: u64,
+}
+
+impl EntryPointSpec {
+    fn new(address: &str, version: EntryPointVersion, deployed_block_number: u64) -> Self {
+        Self {
+            address: Address::from_str(address).expect("Const is formal address"),
+            version,
+            deployed_block_number,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct ChainSpec {
     pub chain_id: u64,
     pub name: String,
-    pub contract_deployed_block_number: u64,
+    pub entry_points: Vec<EntryPointSpec>,
 }
 
 lazy_static! {
@@ -14,12 +37,19 @@ lazy_static! {
         ChainSpec {
             chain_id: 1,
             name: "ETH".to_string(),
-            contract_deployed_block_number: 17012204u64
+            entry_points: vec![
+                EntryPointSpec::new(ENTRY_POINT_ADDR, EntryPointVersion::V0_6, 17012204u64),
+                EntryPointSpec::new(ENTRY_POINT_V07_ADDR, EntryPointVersion::V0_7, 19148796u64),
+            ],
         },
         ChainSpec {
             chain_id: 5,
             name: "Goerli".to_string(),
-            contract_deployed_block_number: 8801632u64
+            entry_points: vec![EntryPointSpec::new(
+                ENTRY_POINT_ADDR,
+                EntryPointVersion::V0_6,
+                8801632u64
+            )],
         }
     ];
 }
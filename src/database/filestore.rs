@@ -1,54 +1,677 @@
-use crate::uo::UserOperationData;
+use crate::uo::{EntryPointEventData, UserOperationData};
 use async_trait::async_trait;
-use std::{fs, io::Write, path::PathBuf};
+use ethers::types::H256;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-use super::{DataBase, UoError};
+use tracing::warn;
+
+use super::{
+    legacy_store,
+    segments::{self, SEGMENT_FOLDER},
+    Cursor, DataBase, Page, StoreMetadata, UoError, UoFilter,
+};
 
 const LAST_BLOCK_FILE: &str = "last-block";
+const METADATA_FILE: &str = "metadata.json";
+const EVENT_FOLDER: &str = "events";
+/// Where the files of a change are written before it is committed.
+const STAGING_FOLDER: &str = "staging";
+/// A committed change that is not fully moved into place yet.
+const COMMIT_FOLDER: &str = "commit";
+/// Block number a committed rollback removes everything above.
+const ROLLBACK_FILE: &str = "rollback";
 
+/// Stores every user operation and event as a json file, sharded into folders named after the
+/// first byte of the uo hash or transaction hash.
+///
+/// A change is first written and synced to a staging folder, which is then renamed into the
+/// commit folder. That rename is the commit: a change found in the commit folder is moved into
+/// place, again on the next start if the process stopped in between, and a leftover staging
+/// folder is dropped. Every file is only ever replaced by a rename, so a power loss leaves
+/// either the old or the new content.
+///
+/// With `Layout::Segments`, records are appended to json lines segments instead, see
+/// `segments`. Records at or below the last block are stored already and skipped.
+///
+/// There are no indexes. Lists read every user operation, apart from block ranges of the
+/// segments layout which only read the segments covering them, and so does a hash lookup in the
+/// segments layout. They fail instead above `MAX_SCANNED_RECORDS`, large stores have to be
+/// queried with another backend.
 pub struct FileDB {
     folder: PathBuf,
+    layout: Layout,
+    max_scanned: usize,
+}
+
+/// Most user operations a query reads before giving up.
+pub const MAX_SCANNED_RECORDS: usize = 100_000;
+
+pub(super) fn too_many_records(max: usize) -> UoError {
+    UoError(format!(
+        "The query has to read more than {max} user operations, the file store has no indexes. Use the RocksDB, MongoDB or PostgreSQL backend to query large stores"
+    ))
+}
+
+/// How `FileDB` lays out records on disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Layout {
+    /// One json file per record.
+    Files,
+    /// Json lines segments covering `blocks` blocks each.
+    Segments { blocks: u64 },
+}
+
+fn shard_of(hash: &H256) -> String {
+    format!("{:02x}", hash.as_bytes()[0])
+}
+
+pub(super) fn sync_dir(path: &Path) -> std::io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
+pub(super) fn write_synced(path: PathBuf, data: &[u8]) -> Result<(), UoError> {
+    let mut fd = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    fd.write_all(data)?;
+    fd.sync_all()?;
+    Ok(())
+}
+
+fn write_json<T: Serialize>(path: PathBuf, value: &T) -> Result<(), UoError> {
+    let data = serde_json::to_vec(value).map_err(|e| UoError(e.to_string()))?;
+    write_synced(path, &data)
+}
+
+/// The last block file holds the block number, followed by its hash when it's known.
+fn checkpoint(block_number: u64, hash: Option<H256>) -> String {
+    match hash {
+        Some(hash) => format!("{block_number} {hash:?}"),
+        None => block_number.to_string(),
+    }
+}
+
+fn read_checkpoint(path: &Path) -> Result<(u64, Option<H256>), UoError> {
+    let corrupted = |e: &dyn Display| UoError(format!("{} is corrupted: {e}", path.display()));
+    let data = fs::read_to_string(path)?;
+    let mut parts = data.split_whitespace();
+    let block_number = parts
+        .next()
+        .unwrap_or_default()
+        .parse::<u64>()
+        .map_err(|e| corrupted(&e))?;
+    let hash = parts
+        .next()
+        .map(H256::from_str)
+        .transpose()
+        .map_err(|e| corrupted(&e))?;
+    Ok((block_number, hash))
+}
+
+fn read_block_number(path: &Path) -> Result<u64, UoError> {
+    read_checkpoint(path).map(|(block_number, _)| block_number)
+}
+
+/// Sync `path` and every folder below it.
+fn sync_tree(path: &Path) -> std::io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            sync_tree(&path)?;
+        }
+    }
+    sync_dir(path)
+}
+
+/// Every record file in `folder`, in the shard folders as well as files written before the
+/// layout was sharded.
+fn record_files(folder: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                files.push(entry?.path());
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Whether `folder` holds records of the first file layout, named after their abbreviated uo
+/// hash like `0x1234…abcd`, where the current ones start with the chain id.
+fn has_legacy_records(folder: &Path) -> std::io::Result<bool> {
+    if !folder.exists() {
+        return Ok(false);
+    }
+    for entry in fs::read_dir(folder)? {
+        if entry?.file_name().to_string_lossy().starts_with("0x") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
 }
 
 #[async_trait]
 impl DataBase for FileDB {
+    /// Falls back to the highest stored block if the last block file is unreadable, the range
+    /// above it is indexed again.
     async fn get_last_block(&self) -> Result<u64, UoError> {
         let f = self.folder.join(LAST_BLOCK_FILE);
-        if f.exists() {
-            let data = fs::read_to_string(f)?;
-            Ok(data.parse::<u64>().expect("block file data corrupted"))
-        } else {
-            Ok(0)
+        if !f.exists() {
+            return Ok(0);
+        }
+        match read_block_number(&f) {
+            Ok(block_number) => Ok(block_number),
+            Err(e) => {
+                let recovered = self.highest_stored_block()?;
+                warn!("{e}, continuing from the highest stored block {recovered}");
+                Ok(recovered)
+            }
         }
     }
 
-    async fn write_last_block(&self, block_number: u64) -> Result<(), UoError> {
+    /// Unknown if the last block file is unreadable.
+    async fn get_last_block_hash(&self) -> Result<Option<H256>, UoError> {
         let f = self.folder.join(LAST_BLOCK_FILE);
-        let mut fd = fs::OpenOptions::new().write(true).create(true).open(f)?;
-        fd.write_fmt(format_args!("{block_number}"))?;
-        fd.flush()?;
-        Ok(())
+        if !f.exists() {
+            return Ok(None);
+        }
+        Ok(read_checkpoint(&f).map_or(None, |(_, hash)| hash))
     }
 
-    async fn write_user_operation(&self, uos: Vec<UserOperationData>) -> Result<(), UoError> {
-        for uo in uos {
-            let f = self.folder.join("data").join(uo.uo_hash.to_string());
-            let mut fd = fs::OpenOptions::new().write(true).create(true).open(f)?;
-            fd.write_all(
-                serde_json::to_string(&uo)
-                    .map_err(|e| UoError(e.to_string()))?
-                    .as_bytes(),
+    async fn commit(
+        &self,
+        uos: Vec<UserOperationData>,
+        events: Vec<EntryPointEventData>,
+        last_block: u64,
+        last_hash: H256,
+    ) -> Result<(), UoError> {
+        let staging = self.stage()?;
+        if let Layout::Segments { blocks } = self.layout {
+            let stored = self.get_last_block().await?;
+            let uos: Vec<_> = uos
+                .into_iter()
+                .filter(|uo| uo.block_number > stored)
+                .collect();
+            let events: Vec<_> = events
+                .into_iter()
+                .filter(|event| event.block_number > stored)
+                .collect();
+            segments::stage(&self.folder, &staging, &uos, &events, blocks)?;
+            write_synced(
+                staging.join(LAST_BLOCK_FILE),
+                checkpoint(last_block, Some(last_hash)).as_bytes(),
             )?;
-            fd.flush()?;
+            return self.apply_staged();
+        }
+        for uo in uos {
+            let shard = staging.join("data").join(shard_of(&uo.uo_hash));
+            fs::create_dir_all(&shard)?;
+            write_json(shard.join(uo.key()), &uo)?;
+        }
+        for event in events {
+            let shard = staging
+                .join(EVENT_FOLDER)
+                .join(shard_of(&event.transaction_hash));
+            fs::create_dir_all(&shard)?;
+            write_json(shard.join(event.key()), &event)?;
+        }
+        write_synced(
+            staging.join(LAST_BLOCK_FILE),
+            checkpoint(last_block, Some(last_hash)).as_bytes(),
+        )?;
+        self.apply_staged()
+    }
+
+    async fn rollback(&self, block_number: u64, hash: Option<H256>) -> Result<(), UoError> {
+        let staging = self.stage()?;
+        write_synced(
+            staging.join(ROLLBACK_FILE),
+            block_number.to_string().as_bytes(),
+        )?;
+        write_synced(
+            staging.join(LAST_BLOCK_FILE),
+            checkpoint(block_number, hash).as_bytes(),
+        )?;
+        self.apply_staged()
+    }
+
+    async fn flush(&self) -> Result<(), UoError> {
+        // Changes are synced when they are committed.
+        Ok(())
+    }
+
+    async fn get_metadata(&self) -> Result<Option<StoreMetadata>, UoError> {
+        let path = self.folder.join(METADATA_FILE);
+        if !path.exists() {
+            return Ok(None);
         }
+        let metadata = serde_json::from_slice(&fs::read(&path)?)
+            .map_err(|e| UoError(format!("{} is corrupted: {e}", path.display())))?;
+        Ok(Some(metadata))
+    }
+
+    fn layout(&self) -> Option<Layout> {
+        Some(self.layout)
+    }
+
+    async fn put_metadata(&self, metadata: &StoreMetadata) -> Result<(), UoError> {
+        let path = self.folder.join(METADATA_FILE);
+        let tmp = path.with_extension("tmp");
+        write_json(tmp.clone(), metadata)?;
+        fs::rename(tmp, path)?;
+        sync_dir(&self.folder)?;
         Ok(())
     }
+
+    async fn get_user_operation(
+        &self,
+        uo_hash: H256,
+    ) -> Result<Option<UserOperationData>, UoError> {
+        if let Layout::Segments { .. } = self.layout {
+            let uos = segments::user_operations(&self.folder, 0, u64::MAX, self.max_scanned)?;
+            return Ok(uos.into_iter().find(|uo| uo.uo_hash == uo_hash));
+        }
+        // File names start with the chain id, look in the shard of the hash and at the files
+        // written before the layout was sharded.
+        let suffix = format!("-{uo_hash:?}");
+        let data = self.folder.join("data");
+        let shard = data.join(shard_of(&uo_hash));
+        let mut candidates = Vec::new();
+        for folder in [shard.as_path(), data.as_path()] {
+            if folder.exists() {
+                for entry in fs::read_dir(folder)? {
+                    candidates.push(entry?.path());
+                }
+            }
+        }
+        for path in candidates {
+            let matches = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.ends_with(&suffix));
+            if matches && path.is_file() {
+                let uo = serde_json::from_slice(&fs::read(&path)?)
+                    .map_err(|e| UoError(format!("{}: {e}", path.display())))?;
+                return Ok(Some(uo));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn list_user_operations(
+        &self,
+        filter: &UoFilter,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page<UserOperationData>, UoError> {
+        if let Layout::Segments { .. } = self.layout {
+            let (from, to) = match *filter {
+                UoFilter::Blocks { from, to } => (from, to),
+                _ => (0, u64::MAX),
+            };
+            let uos = segments::user_operations(&self.folder, from, to, self.max_scanned)?;
+            return Ok(Page::scan(uos, filter, after, limit));
+        }
+        let files = record_files(&self.folder.join("data"))?;
+        if files.len() > self.max_scanned {
+            return Err(too_many_records(self.max_scanned));
+        }
+        let mut uos = Vec::new();
+        for path in files {
+            match serde_json::from_slice::<UserOperationData>(&fs::read(&path)?) {
+                Ok(uo) => uos.push(uo),
+                Err(e) => warn!("Skipping unreadable record {}: {e}", path.display()),
+            }
+        }
+        Ok(Page::scan(uos, filter, after, limit))
+    }
 }
 
 impl FileDB {
-    pub fn new(path: PathBuf) -> Result<Self, std::io::Error> {
+    pub fn new(path: PathBuf, layout: Layout) -> Result<Self, UoError> {
         fs::create_dir_all(path.clone())?;
-        fs::create_dir_all(path.join("data"))?;
-        Ok(Self { folder: path })
+        if has_legacy_records(&path.join("data"))? {
+            return Err(legacy_store(path.display()));
+        }
+        match layout {
+            Layout::Files => {
+                fs::create_dir_all(path.join("data"))?;
+                fs::create_dir_all(path.join(EVENT_FOLDER))?;
+            }
+            Layout::Segments { .. } => {
+                fs::create_dir_all(path.join(SEGMENT_FOLDER))?;
+                segments::remove_temp_files(&path)?;
+            }
+        }
+        let db = Self {
+            folder: path,
+            layout,
+            max_scanned: MAX_SCANNED_RECORDS,
+        };
+        db.replay()?;
+        let staging = db.folder.join(STAGING_FOLDER);
+        if staging.exists() {
+            warn!("Dropping a change that was not committed before the last stop");
+            fs::remove_dir_all(staging)?;
+        }
+        Ok(db)
+    }
+
+    /// Create an empty staging folder.
+    fn stage(&self) -> Result<PathBuf, UoError> {
+        let staging = self.folder.join(STAGING_FOLDER);
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir_all(staging.join("data"))?;
+        fs::create_dir_all(staging.join(EVENT_FOLDER))?;
+        Ok(staging)
+    }
+
+    fn apply_staged(&self) -> Result<(), UoError> {
+        let staging = self.folder.join(STAGING_FOLDER);
+        sync_tree(&staging)?;
+        fs::rename(staging, self.folder.join(COMMIT_FOLDER))?;
+        sync_dir(&self.folder)?;
+        self.replay()
+    }
+
+    /// Move the committed change into place. Every step can run again, so an interrupted
+    /// change is finished by running it once more.
+    fn replay(&self) -> Result<(), UoError> {
+        let commit = self.folder.join(COMMIT_FOLDER);
+        if !commit.exists() {
+            return Ok(());
+        }
+        let rollback = commit.join(ROLLBACK_FILE);
+        if let Layout::Segments { .. } = self.layout {
+            if rollback.exists() {
+                segments::remove_above(&self.folder, read_block_number(&rollback)?)?;
+            }
+            segments::append(&self.folder, &commit)?;
+            segments::write_index(&self.folder)?;
+            return self.finish_replay(&commit);
+        }
+        if rollback.exists() {
+            let block_number = read_block_number(&rollback)?;
+            self.remove_above::<UserOperationData>("data", block_number, |uo| uo.block_number)?;
+            self.remove_above::<EntryPointEventData>(EVENT_FOLDER, block_number, |event| {
+                event.block_number
+            })?;
+        }
+        for folder in ["data", EVENT_FOLDER] {
+            for shard in fs::read_dir(commit.join(folder))? {
+                let shard = shard?;
+                let target = self.folder.join(folder).join(shard.file_name());
+                fs::create_dir_all(&target)?;
+                for entry in fs::read_dir(shard.path())? {
+                    let entry = entry?;
+                    fs::rename(entry.path(), target.join(entry.file_name()))?;
+                }
+                sync_dir(&target)?;
+            }
+            sync_dir(&self.folder.join(folder))?;
+        }
+        self.finish_replay(&commit)
+    }
+
+    /// Move the last block of the committed change into place and drop the change.
+    fn finish_replay(&self, commit: &Path) -> Result<(), UoError> {
+        let last_block = commit.join(LAST_BLOCK_FILE);
+        if last_block.exists() {
+            fs::rename(last_block, self.folder.join(LAST_BLOCK_FILE))?;
+        }
+        fs::remove_dir_all(commit)?;
+        sync_dir(&self.folder)?;
+        Ok(())
+    }
+
+    /// Delete every json file in `folder` whose block number is above `block_number`.
+    fn remove_above<T: DeserializeOwned>(
+        &self,
+        folder: &str,
+        block_number: u64,
+        block_of: impl Fn(&T) -> u64,
+    ) -> Result<(), UoError> {
+        for path in record_files(&self.folder.join(folder))? {
+            match serde_json::from_slice::<T>(&fs::read(&path)?) {
+                Ok(record) if block_of(&record) > block_number => fs::remove_file(path)?,
+                Ok(_) => {}
+                Err(e) => warn!("Skipping unreadable record {}: {e}", path.display()),
+            }
+        }
+        Ok(())
+    }
+
+    fn highest_stored_block(&self) -> Result<u64, UoError> {
+        if let Layout::Segments { .. } = self.layout {
+            return segments::highest_block(&self.folder);
+        }
+        let mut highest = 0;
+        for path in record_files(&self.folder.join("data"))? {
+            if let Ok(uo) = serde_json::from_slice::<UserOperationData>(&fs::read(&path)?) {
+                highest = highest.max(uo.block_number);
+            }
+        }
+        for path in record_files(&self.folder.join(EVENT_FOLDER))? {
+            if let Ok(event) = serde_json::from_slice::<EntryPointEventData>(&fs::read(&path)?) {
+                highest = highest.max(event.block_number);
+            }
+        }
+        Ok(highest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use ethers::types::{Address, H256};
+
+    use super::{
+        record_files, shard_of, FileDB, Layout, COMMIT_FOLDER, LAST_BLOCK_FILE, SEGMENT_FOLDER,
+        STAGING_FOLDER,
+    };
+    use crate::{
+        database::{DataBase, UoFilter},
+        test_utils::{block_hash, temp_dir, user_operation_data},
+    };
+
+    #[tokio::test]
+    async fn commit_and_rollback() {
+        let dir = temp_dir("filedb-commit");
+        let db = FileDB::new(dir.clone(), Layout::Files).unwrap();
+        db.commit(vec![user_operation_data(0, 10)], vec![], 10, block_hash(10))
+            .await
+            .unwrap();
+        db.commit(vec![user_operation_data(1, 20)], vec![], 20, block_hash(20))
+            .await
+            .unwrap();
+        // Committing the same range again leaves the same state.
+        db.commit(vec![user_operation_data(1, 20)], vec![], 20, block_hash(20))
+            .await
+            .unwrap();
+        assert_eq!(db.get_last_block().await.unwrap(), 20);
+        assert_eq!(record_files(&dir.join("data")).unwrap().len(), 2);
+        let uo = user_operation_data(1, 20);
+        assert!(dir
+            .join("data")
+            .join(shard_of(&uo.uo_hash))
+            .join(uo.key())
+            .exists());
+
+        assert_eq!(
+            db.get_last_block_hash().await.unwrap(),
+            Some(block_hash(20))
+        );
+
+        db.rollback(15, None).await.unwrap();
+        assert_eq!(db.get_last_block().await.unwrap(), 15);
+        assert_eq!(db.get_last_block_hash().await.unwrap(), None);
+        assert_eq!(record_files(&dir.join("data")).unwrap().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recover_interrupted_commit() {
+        let dir = temp_dir("filedb-recover");
+        let db = FileDB::new(dir.clone(), Layout::Files).unwrap();
+        db.commit(vec![user_operation_data(0, 10)], vec![], 10, block_hash(10))
+            .await
+            .unwrap();
+
+        // A commit that stopped while being moved into place is finished on start.
+        let commit = dir.join(COMMIT_FOLDER);
+        fs::create_dir_all(commit.join("data").join("ab")).unwrap();
+        fs::create_dir_all(commit.join("events")).unwrap();
+        fs::copy(
+            &record_files(&dir.join("data")).unwrap()[0],
+            commit.join("data").join("ab").join("other"),
+        )
+        .unwrap();
+        fs::write(commit.join(LAST_BLOCK_FILE), "30").unwrap();
+        // A change that was never committed is dropped.
+        fs::create_dir_all(dir.join(STAGING_FOLDER).join("data")).unwrap();
+        fs::write(dir.join(STAGING_FOLDER).join(LAST_BLOCK_FILE), "40").unwrap();
+
+        let db = FileDB::new(dir.clone(), Layout::Files).unwrap();
+        assert_eq!(db.get_last_block().await.unwrap(), 30);
+        assert_eq!(record_files(&dir.join("data")).unwrap().len(), 2);
+        assert!(!commit.exists());
+        assert!(!dir.join(STAGING_FOLDER).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recover_corrupted_last_block() {
+        let dir = temp_dir("filedb-corrupted");
+        let db = FileDB::new(dir.clone(), Layout::Files).unwrap();
+        db.commit(vec![user_operation_data(0, 10)], vec![], 12, block_hash(12))
+            .await
+            .unwrap();
+        fs::write(dir.join(LAST_BLOCK_FILE), "12\0\0garbage").unwrap();
+
+        assert_eq!(db.get_last_block().await.unwrap(), 10);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn append_segments() {
+        let dir = temp_dir("filedb-segments");
+        let db = FileDB::new(dir.clone(), Layout::Segments { blocks: 100 }).unwrap();
+        db.commit(
+            vec![user_operation_data(0, 10), user_operation_data(1, 150)],
+            vec![],
+            150,
+            block_hash(150),
+        )
+        .await
+        .unwrap();
+        // The next range starts at the last block again, its records are not appended twice.
+        db.commit(
+            vec![user_operation_data(1, 150), user_operation_data(2, 160)],
+            vec![],
+            160,
+            block_hash(160),
+        )
+        .await
+        .unwrap();
+        let segments = dir.join(SEGMENT_FOLDER);
+        let lines = |name: &str| {
+            fs::read_to_string(segments.join(name))
+                .unwrap()
+                .lines()
+                .count()
+        };
+        assert_eq!(lines("user_operations-000000000000-000000000099.jsonl"), 1);
+        assert_eq!(lines("user_operations-000000000100-000000000199.jsonl"), 2);
+        let index = fs::read_to_string(segments.join("index.json")).unwrap();
+        assert!(index.contains("\"from_block\": 100"));
+
+        db.rollback(155, None).await.unwrap();
+        assert_eq!(db.get_last_block().await.unwrap(), 155);
+        assert_eq!(lines("user_operations-000000000100-000000000199.jsonl"), 1);
+        db.rollback(50, None).await.unwrap();
+        assert!(!segments
+            .join("user_operations-000000000100-000000000199.jsonl")
+            .exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn query_user_operations() {
+        for layout in [Layout::Files, Layout::Segments { blocks: 10 }] {
+            let dir = temp_dir("filedb-query");
+            let db = FileDB::new(dir.clone(), layout).unwrap();
+            let mut deployed = user_operation_data(2, 25);
+            deployed.factory = Some(Address::from_low_u64_be(9));
+            db.commit(
+                vec![
+                    user_operation_data(0, 5),
+                    user_operation_data(1, 15),
+                    deployed,
+                ],
+                vec![],
+                25,
+                block_hash(25),
+            )
+            .await
+            .unwrap();
+
+            let uo = db.get_user_operation(H256::from_low_u64_be(0x1001)).await;
+            assert_eq!(uo.unwrap().unwrap().block_number, 15);
+            assert!(db
+                .get_user_operation(H256::from_low_u64_be(1))
+                .await
+                .unwrap()
+                .is_none());
+
+            let sender = UoFilter::Sender(Address::from_low_u64_be(2));
+            let page = db.list_user_operations(&sender, None, 2).await.unwrap();
+            assert_eq!(page.items.len(), 2);
+            let page = db
+                .list_user_operations(&sender, page.next, 2)
+                .await
+                .unwrap();
+            assert_eq!(page.items[0].block_number, 25);
+            assert_eq!(page.next, None);
+
+            let blocks = UoFilter::Blocks { from: 10, to: 20 };
+            let page = db.list_user_operations(&blocks, None, 10).await.unwrap();
+            assert_eq!(page.items.len(), 1);
+            let factory = UoFilter::Factory(Address::from_low_u64_be(9));
+            let page = db.list_user_operations(&factory, None, 10).await.unwrap();
+            assert_eq!(page.items[0].block_number, 25);
+
+            // Queries reading more than the cap fail instead of scanning on.
+            let db = FileDB {
+                max_scanned: 2,
+                ..db
+            };
+            assert!(db.list_user_operations(&sender, None, 10).await.is_err());
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn refuse_legacy_records() {
+        let dir = temp_dir("filedb-legacy");
+        fs::create_dir_all(dir.join("data")).unwrap();
+        fs::write(dir.join("data").join("0x1234…abcd"), "{}").unwrap();
+        let error = FileDB::new(dir, Layout::Files).err().unwrap();
+        assert!(error.to_string().contains("new store"));
     }
 }
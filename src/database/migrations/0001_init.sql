@@ -0,0 +1,83 @@
+-- Chain and EntryPoints the store holds, a single row. Chains sharing a database use one schema
+-- each.
+CREATE TABLE store_metadata (
+    singleton BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (singleton),
+    chain_id BIGINT NOT NULL,
+    entry_points BYTEA[] NOT NULL,
+    schema_version INTEGER NOT NULL
+);
+
+-- Last indexed block and its hash, a single row. The hash is unknown after some rollbacks.
+CREATE TABLE checkpoint (
+    singleton BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (singleton),
+    last_block BIGINT NOT NULL,
+    last_hash BYTEA
+);
+
+-- Transactions that emitted EntryPoint logs, bundles for the most part.
+CREATE TABLE bundles (
+    chain_id BIGINT NOT NULL,
+    transaction_hash BYTEA NOT NULL,
+    transaction_index BIGINT NOT NULL,
+    block_number BIGINT NOT NULL,
+    block_hash BYTEA NOT NULL,
+    PRIMARY KEY (chain_id, transaction_hash)
+);
+CREATE INDEX bundles_block_number ON bundles (block_number);
+
+CREATE TABLE user_operations (
+    chain_id BIGINT NOT NULL,
+    uo_hash BYTEA NOT NULL,
+    entry_point BYTEA NOT NULL,
+    entry_point_version TEXT NOT NULL,
+    sender BYTEA NOT NULL,
+    nonce NUMERIC(78, 0) NOT NULL,
+    init_code BYTEA NOT NULL,
+    call_data BYTEA NOT NULL,
+    call_gas_limit NUMERIC(78, 0) NOT NULL,
+    verification_gas_limit NUMERIC(78, 0) NOT NULL,
+    pre_verification_gas NUMERIC(78, 0) NOT NULL,
+    max_fee_per_gas NUMERIC(78, 0) NOT NULL,
+    max_priority_fee_per_gas NUMERIC(78, 0) NOT NULL,
+    paymaster_and_data BYTEA NOT NULL,
+    signature BYTEA NOT NULL,
+    paymaster BYTEA NOT NULL,
+    factory BYTEA,
+    success BOOLEAN NOT NULL,
+    actual_gas_cost NUMERIC(78, 0) NOT NULL,
+    actual_gas_price NUMERIC(78, 0) NOT NULL,
+    transaction_hash BYTEA NOT NULL,
+    log_index BIGINT NOT NULL,
+    block_number BIGINT NOT NULL,
+    aggregator BYTEA,
+    aggregated_signature BYTEA,
+    PRIMARY KEY (chain_id, uo_hash),
+    FOREIGN KEY (chain_id, transaction_hash) REFERENCES bundles ON DELETE CASCADE
+);
+CREATE INDEX user_operations_uo_hash ON user_operations (uo_hash);
+CREATE INDEX user_operations_block ON user_operations (block_number, log_index);
+CREATE INDEX user_operations_sender ON user_operations (sender, block_number, log_index);
+CREATE INDEX user_operations_paymaster ON user_operations (paymaster, block_number, log_index);
+CREATE INDEX user_operations_factory ON user_operations (factory, block_number, log_index);
+CREATE INDEX user_operations_transaction ON user_operations (transaction_hash, log_index);
+
+-- Every EntryPoint event apart from UserOperationEvent. Numeric arguments have their own
+-- columns, set for the events that carry them, and the other arguments are json.
+CREATE TABLE events (
+    chain_id BIGINT NOT NULL,
+    transaction_hash BYTEA NOT NULL,
+    log_index BIGINT NOT NULL,
+    entry_point BYTEA NOT NULL,
+    event TEXT NOT NULL,
+    args JSONB NOT NULL,
+    nonce NUMERIC(78, 0),
+    total_deposit NUMERIC(78, 0),
+    amount NUMERIC(78, 0),
+    total_staked NUMERIC(78, 0),
+    unstake_delay_sec NUMERIC(78, 0),
+    withdraw_time NUMERIC(78, 0),
+    block_number BIGINT NOT NULL,
+    PRIMARY KEY (chain_id, transaction_hash, log_index),
+    FOREIGN KEY (chain_id, transaction_hash) REFERENCES bundles ON DELETE CASCADE
+);
+CREATE INDEX events_block_number ON events (block_number);
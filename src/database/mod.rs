@@ -1,20 +1,30 @@
 pub mod filestore;
 pub mod mongodb;
+pub mod postgres;
+pub mod query;
 pub mod rocksdb_storage;
+mod segments;
 
-use crate::uo::UserOperationData;
+use crate::uo::{EntryPointEventData, UserOperationData};
 use ::mongodb::error::Error;
 use async_trait::async_trait;
-pub use filestore::FileDB;
+use ethers::types::{Address, H256};
+pub use filestore::{FileDB, Layout};
+pub use query::{Cursor, Page, UoFilter};
+use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use thiserror::Error;
+use tracing::warn;
+
+/// Version of the way records are stored, bumped when a store has to be migrated.
+pub const SCHEMA_VERSION: u32 = 1;
 
 #[derive(Error, Debug)]
 pub struct UoError(String);
 
 impl Display for UoError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self:?}")
+        write!(f, "{}", self.0)
     }
 }
 
@@ -30,17 +40,87 @@ impl From<Error> for UoError {
     }
 }
 
+impl From<tokio_postgres::Error> for UoError {
+    fn from(value: tokio_postgres::Error) -> Self {
+        UoError(value.to_string())
+    }
+}
+
 impl From<rocksdb::Error> for UoError {
     fn from(value: rocksdb::Error) -> Self {
         UoError(value.into_string())
     }
 }
 
+/// What a store holds, recorded on its first start so it is never continued for another chain or
+/// other EntryPoints.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreMetadata {
+    pub chain_id: u64,
+    /// Sorted addresses of the indexed EntryPoints.
+    pub entry_points: Vec<Address>,
+    pub schema_version: u32,
+    /// Layout of the records, for backends that can be opened with several.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layout: Option<Layout>,
+}
+
+impl StoreMetadata {
+    pub fn new(chain_id: u64, mut entry_points: Vec<Address>) -> Self {
+        entry_points.sort();
+        entry_points.dedup();
+        Self {
+            chain_id,
+            entry_points,
+            schema_version: SCHEMA_VERSION,
+            layout: None,
+        }
+    }
+}
+
+/// Error for a store written before records were keyed by chain id. Its records miss fields, like
+/// the log index or the paymaster, that only the chain has, so it can't be upgraded in place.
+pub(crate) fn legacy_store(store: impl Display) -> UoError {
+    UoError(format!(
+        "{store} was written by an indexer version from before records were keyed by chain id. Its records can't be upgraded, index into a new store instead"
+    ))
+}
+
 #[async_trait]
 pub trait DataBase {
     async fn get_last_block(&self) -> Result<u64, UoError>;
-    async fn write_user_operation(&self, uos: Vec<UserOperationData>) -> Result<(), UoError>;
-    async fn write_last_block(&self, block_number: u64) -> Result<(), UoError>;
+    /// Hash of the last block, `None` when it isn't known.
+    async fn get_last_block_hash(&self) -> Result<Option<H256>, UoError>;
+    /// Store the user operations and events of a range together with the new last block and its
+    /// hash. Either all of them are written or none is.
+    async fn commit(
+        &self,
+        uos: Vec<UserOperationData>,
+        events: Vec<EntryPointEventData>,
+        last_block: u64,
+        last_hash: H256,
+    ) -> Result<(), UoError>;
+    /// Remove every stored user operation and event above `block_number` and move the last block
+    /// back to it with its hash, if known, in one step.
+    async fn rollback(&self, block_number: u64, hash: Option<H256>) -> Result<(), UoError>;
+    /// Make everything written so far durable, called before the process exits.
+    async fn flush(&self) -> Result<(), UoError>;
+    async fn get_metadata(&self) -> Result<Option<StoreMetadata>, UoError>;
+    /// Layout the records are written with, when the backend can be opened with several.
+    fn layout(&self) -> Option<Layout> {
+        None
+    }
+    async fn put_metadata(&self, metadata: &StoreMetadata) -> Result<(), UoError>;
+    async fn get_user_operation(&self, uo_hash: H256)
+        -> Result<Option<UserOperationData>, UoError>;
+    /// Up to `limit` user operations matching `filter` after the `after` cursor, ordered by block
+    /// number and log index.
+    async fn list_user_operations(
+        &self,
+        filter: &UoFilter,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page<UserOperationData>, UoError>;
 }
 
 pub struct Storage {
@@ -57,10 +137,177 @@ impl Storage {
     pub async fn get_last_block(&self) -> Result<u64, UoError> {
         self.inner.get_last_block().await
     }
-    pub async fn write_user_operation(&self, uos: Vec<UserOperationData>) -> Result<(), UoError> {
-        self.inner.write_user_operation(uos).await
+    pub async fn get_last_block_hash(&self) -> Result<Option<H256>, UoError> {
+        self.inner.get_last_block_hash().await
+    }
+    pub async fn commit(
+        &self,
+        uos: Vec<UserOperationData>,
+        events: Vec<EntryPointEventData>,
+        last_block: u64,
+        last_hash: H256,
+    ) -> Result<(), UoError> {
+        self.inner.commit(uos, events, last_block, last_hash).await
+    }
+    pub async fn rollback(&self, block_number: u64, hash: Option<H256>) -> Result<(), UoError> {
+        self.inner.rollback(block_number, hash).await
+    }
+    pub async fn flush(&self) -> Result<(), UoError> {
+        self.inner.flush().await
     }
-    pub async fn write_last_block(&self, block_number: u64) -> Result<(), UoError> {
-        self.inner.write_last_block(block_number).await
+    /// Record `expected` in a new store, and refuse a store recorded with other metadata. A
+    /// store with indexed blocks but no metadata is refused too, unless `adopt` is set.
+    pub async fn check_metadata(
+        &self,
+        expected: &StoreMetadata,
+        adopt: bool,
+    ) -> Result<(), UoError> {
+        let expected = StoreMetadata {
+            layout: self.inner.layout(),
+            ..expected.clone()
+        };
+        match self.inner.get_metadata().await? {
+            Some(stored) if stored == expected => Ok(()),
+            Some(stored) => Err(UoError(format!(
+                "The store holds chain {} with EntryPoints {:?}, schema version {} and layout {:?}, it can't be used for chain {} with EntryPoints {:?}, schema version {} and layout {:?}",
+                stored.chain_id,
+                stored.entry_points,
+                stored.schema_version,
+                stored.layout,
+                expected.chain_id,
+                expected.entry_points,
+                expected.schema_version,
+                expected.layout,
+            ))),
+            None => {
+                let last_block = self.inner.get_last_block().await?;
+                if last_block > 0 && !adopt {
+                    return Err(UoError(format!(
+                        "The store has blocks up to {last_block} indexed but no metadata, so its chain and EntryPoints are unknown. Pass --adopt-store to record chain {} with EntryPoints {:?} for it",
+                        expected.chain_id, expected.entry_points
+                    )));
+                }
+                if last_block > 0 {
+                    warn!(
+                        "Adopting a store without metadata, assuming its blocks up to {last_block} are of chain {}",
+                        expected.chain_id
+                    );
+                }
+                self.inner.put_metadata(&expected).await
+            }
+        }
+    }
+    pub async fn get_user_operation(
+        &self,
+        uo_hash: H256,
+    ) -> Result<Option<UserOperationData>, UoError> {
+        self.inner.get_user_operation(uo_hash).await
+    }
+    pub async fn list_by_sender(
+        &self,
+        sender: Address,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page<UserOperationData>, UoError> {
+        self.inner
+            .list_user_operations(&UoFilter::Sender(sender), after, limit)
+            .await
+    }
+    pub async fn list_by_transaction(
+        &self,
+        transaction_hash: H256,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page<UserOperationData>, UoError> {
+        self.inner
+            .list_user_operations(&UoFilter::TransactionHash(transaction_hash), after, limit)
+            .await
+    }
+    /// User operations from block `from` to block `to`, both included.
+    pub async fn list_by_blocks(
+        &self,
+        from: u64,
+        to: u64,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page<UserOperationData>, UoError> {
+        self.inner
+            .list_user_operations(&UoFilter::Blocks { from, to }, after, limit)
+            .await
+    }
+    pub async fn list_by_paymaster(
+        &self,
+        paymaster: Address,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page<UserOperationData>, UoError> {
+        self.inner
+            .list_user_operations(&UoFilter::Paymaster(paymaster), after, limit)
+            .await
+    }
+    pub async fn list_by_factory(
+        &self,
+        factory: Address,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page<UserOperationData>, UoError> {
+        self.inner
+            .list_user_operations(&UoFilter::Factory(factory), after, limit)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ethers::types::Address;
+
+    use super::{FileDB, Layout, Storage, StoreMetadata};
+    use crate::test_utils::{block_hash, temp_dir};
+
+    #[tokio::test]
+    async fn refuse_other_chain() {
+        let dir = temp_dir("metadata");
+        let storage =
+            Storage::new(Box::new(FileDB::new(dir.clone(), Layout::Files).unwrap())).await;
+        let entry_points = vec![Address::from_low_u64_be(2), Address::from_low_u64_be(1)];
+        let metadata = StoreMetadata::new(1, entry_points.clone());
+        storage.check_metadata(&metadata, false).await.unwrap();
+        storage
+            .check_metadata(
+                &StoreMetadata::new(1, entry_points.into_iter().rev().collect()),
+                false,
+            )
+            .await
+            .unwrap();
+
+        let goerli = StoreMetadata::new(5, metadata.entry_points.clone());
+        assert!(storage.check_metadata(&goerli, false).await.is_err());
+        let other_entry_points = StoreMetadata::new(1, vec![Address::from_low_u64_be(1)]);
+        assert!(storage
+            .check_metadata(&other_entry_points, false)
+            .await
+            .is_err());
+
+        // The layout is recorded too.
+        let segments = Layout::Segments { blocks: 100 };
+        let storage = Storage::new(Box::new(FileDB::new(dir.clone(), segments).unwrap())).await;
+        assert!(storage.check_metadata(&metadata, false).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn adopt_store_without_metadata() {
+        let dir = temp_dir("metadata-adopt");
+        let storage =
+            Storage::new(Box::new(FileDB::new(dir.clone(), Layout::Files).unwrap())).await;
+        storage
+            .commit(vec![], vec![], 10, block_hash(10))
+            .await
+            .unwrap();
+        let metadata = StoreMetadata::new(1, vec![Address::from_low_u64_be(1)]);
+        assert!(storage.check_metadata(&metadata, false).await.is_err());
+        storage.check_metadata(&metadata, true).await.unwrap();
+        storage.check_metadata(&metadata, false).await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
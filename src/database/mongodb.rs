@@ -1,50 +1,231 @@
-use crate::{uo::UserOperationData, DataBase};
+use crate::uo::{EntryPointEventData, UserOperationData};
 use async_trait::async_trait;
+use ethers::{
+    types::{H256, U256},
+    utils::to_checksum,
+};
+use futures::TryStreamExt;
 use mongodb::{
-    bson::doc,
-    options::{ClientOptions, UpdateOptions},
-    Client,
+    bson::{self, doc, Bson, Decimal128, Document},
+    error::Error,
+    options::{ClientOptions, FindOptions, IndexOptions, ReplaceOptions, UpdateOptions},
+    Client, ClientSession, Database, IndexModel,
 };
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
-use super::UoError;
+use super::{legacy_store, Cursor, DataBase, Page, StoreMetadata, UoError, UoFilter};
 
-const UO_INDEXER_DB: &str = "UoIndexer";
+/// Database used when no namespace is given.
+pub const DEFAULT_NAMESPACE: &str = "UoIndexer";
 const LATEST_BLOCK_NUMBER: &str = "latestBlockNumber";
 const DOC_KEY_INDEX: i32 = 0;
 const UO_COLLECTION: &str = "UserOperation";
+const EVENT_COLLECTION: &str = "EntryPointEvent";
+const STORE_METADATA: &str = "storeMetadata";
+/// Field of user operation documents holding their numbers as Decimal128.
+const NUMERIC: &str = "numeric";
 
+/// Stores everything in the database named after the namespace, so several chains can share a
+/// deployment with one namespace each.
 pub struct MongoDB {
     _cli_options: ClientOptions,
     client: Client,
+    namespace: String,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MetaData {
     latest_block_number: i64,
+    #[serde(default)]
+    latest_block_hash: Option<H256>,
     key: i32,
 }
 
 impl MongoDB {
-    pub async fn new(url: String) -> Result<Self, UoError> {
+    pub async fn new(url: String, namespace: String) -> Result<Self, UoError> {
         let cli_options = ClientOptions::parse(url).await?;
         let client = Client::with_options(cli_options.clone())?;
+        let db = client.database(&namespace);
+        // Records of the first version have no chain id and may be duplicated, which the unique
+        // index below can't be built on.
+        let legacy = db
+            .collection::<Document>(UO_COLLECTION)
+            .find_one(doc! {"chain_id": {"$exists": false}}, None)
+            .await?;
+        if legacy.is_some() {
+            return Err(legacy_store(format!("The {namespace} database")));
+        }
+        let order = doc! {"block_number": 1, "log_index": 1};
+        let by = |field: &str| {
+            let mut keys = doc! {field: 1};
+            keys.extend(order.clone());
+            index(keys)
+        };
+        db.collection::<UserOperationData>(UO_COLLECTION)
+            .create_indexes(
+                [
+                    unique_index(doc! {"chain_id": 1, "uo_hash": 1}),
+                    index(doc! {"uo_hash": 1}),
+                    index(order.clone()),
+                    by("uo.sender"),
+                    by("paymaster"),
+                    by("factory"),
+                    index(doc! {"transaction_hash": 1, "log_index": 1}),
+                ],
+                None,
+            )
+            .await?;
+        db.collection::<EntryPointEventData>(EVENT_COLLECTION)
+            .create_indexes(
+                [
+                    unique_index(doc! {"chain_id": 1, "transaction_hash": 1, "log_index": 1}),
+                    index(doc! {"block_number": 1}),
+                ],
+                None,
+            )
+            .await?;
         Ok(MongoDB {
             _cli_options: cli_options,
             client,
+            namespace,
         })
     }
 }
 
+impl MongoDB {
+    fn db(&self) -> Database {
+        self.client.database(&self.namespace)
+    }
+
+    async fn start_transaction(&self) -> Result<ClientSession, UoError> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await.map_err(|e| {
+            UoError(format!(
+                "MongoDB must run as a replica set to commit ranges atomically: {e}"
+            ))
+        })?;
+        Ok(session)
+    }
+}
+
+fn index(keys: Document) -> IndexModel {
+    IndexModel::builder().keys(keys).build()
+}
+
+fn unique_index(keys: Document) -> IndexModel {
+    IndexModel::builder()
+        .keys(keys)
+        .options(IndexOptions::builder().unique(true).build())
+        .build()
+}
+
+/// Digits a Decimal128 holds exactly.
+const DECIMAL128_DIGITS: usize = 34;
+
+/// `value` as a Decimal128, or null when it has more digits than a Decimal128 holds exactly.
+fn decimal(value: U256) -> Bson {
+    let digits = value.to_string();
+    if digits.len() > DECIMAL128_DIGITS {
+        return Bson::Null;
+    }
+    Bson::Decimal128(Decimal128::from_str(&digits).expect("A Decimal128 holds 34 digits"))
+}
+
+/// Numbers of `uo` as Decimal128, which queries can compare and sum unlike the hex strings serde
+/// writes. Values a Decimal128 can't hold exactly are null, their hex string stays the reference.
+/// The nonce is split into its key and its sequence, the 192 high and the 64 low bits.
+fn numeric_document(uo: &UserOperationData) -> Document {
+    doc! {
+        "nonce_key": decimal(uo.uo.nonce >> 64),
+        "nonce_sequence": decimal(uo.uo.nonce.low_u64().into()),
+        "call_gas_limit": decimal(uo.uo.call_gas_limit),
+        "verification_gas_limit": decimal(uo.uo.verification_gas_limit),
+        "pre_verification_gas": decimal(uo.uo.pre_verification_gas),
+        "max_fee_per_gas": decimal(uo.uo.max_fee_per_gas),
+        "max_priority_fee_per_gas": decimal(uo.uo.max_priority_fee_per_gas),
+        "actual_gas_cost": decimal(uo.actual_gas_cost),
+        "actual_gas_price": decimal(uo.actual_gas_price),
+    }
+}
+
+/// Document stored for `uo`, its serde fields next to their numeric form.
+fn user_operation_document(uo: &UserOperationData) -> Result<Document, UoError> {
+    let mut document = bson::to_document(uo).map_err(|e| UoError(e.to_string()))?;
+    document.insert(NUMERIC, numeric_document(uo));
+    Ok(document)
+}
+
+/// Chain ids are stored the way serde writes a `u64` into bson.
+fn chain_id_bson(chain_id: u64) -> i64 {
+    <i64>::try_from(chain_id).expect("We are far from limitation")
+}
+
+fn u64_bson(number: u64) -> i64 {
+    <i64>::try_from(number).expect("We are far from limitation")
+}
+
+/// Query matching the documents `filter` selects, the way serde writes the fields into bson.
+fn filter_document(filter: &UoFilter) -> Document {
+    match *filter {
+        // The sender is written checksummed, every other address in lowercase.
+        UoFilter::Sender(sender) => doc! {"uo.sender": to_checksum(&sender, None)},
+        UoFilter::TransactionHash(hash) => doc! {"transaction_hash": format!("{hash:?}")},
+        UoFilter::Blocks { from, to } => {
+            doc! {"block_number": {"$gte": u64_bson(from), "$lte": u64_bson(to)}}
+        }
+        UoFilter::Paymaster(paymaster) => doc! {"paymaster": format!("{paymaster:?}")},
+        UoFilter::Factory(factory) => doc! {"factory": format!("{factory:?}")},
+    }
+}
+
+/// Query matching the documents after `cursor`.
+fn after_document(cursor: Cursor) -> Document {
+    let block_number = u64_bson(cursor.block_number);
+    doc! {"$or": [
+        {"block_number": {"$gt": block_number}},
+        {"block_number": block_number, "log_index": {"$gt": u64_bson(cursor.log_index)}},
+    ]}
+}
+
+async fn write_last_block(
+    db: &Database,
+    block_number: u64,
+    hash: Option<H256>,
+    session: &mut ClientSession,
+) -> Result<(), Error> {
+    let number = <i64>::try_from(block_number).expect("We are far from limitation");
+    let hash = hash.map(|hash| format!("{hash:?}"));
+    db.collection::<MetaData>(LATEST_BLOCK_NUMBER)
+        .update_one_with_session(
+            doc! {"key": DOC_KEY_INDEX},
+            doc! {"$set": {"latestBlockNumber": number, "latestBlockHash": hash}},
+            Some(UpdateOptions::builder().upsert(true).build()),
+            session,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Commit the transaction of `session` if `result` is ok, abort it otherwise.
+async fn finish_transaction(
+    mut session: ClientSession,
+    result: Result<(), Error>,
+) -> Result<(), UoError> {
+    match result {
+        Ok(()) => Ok(session.commit_transaction().await?),
+        Err(e) => {
+            session.abort_transaction().await.ok();
+            Err(e.into())
+        }
+    }
+}
+
 #[async_trait]
 impl DataBase for MongoDB {
     async fn get_last_block(&self) -> Result<u64, UoError> {
-        let collection = self
-            .client
-            .clone()
-            .database(UO_INDEXER_DB)
-            .collection::<MetaData>(LATEST_BLOCK_NUMBER);
+        let collection = self.db().collection::<MetaData>(LATEST_BLOCK_NUMBER);
         match collection
             .find_one(doc! {"key": DOC_KEY_INDEX}, None)
             .await?
@@ -56,35 +237,180 @@ impl DataBase for MongoDB {
         }
     }
 
-    async fn write_last_block(&self, block_number: u64) -> Result<(), UoError> {
-        let collection = self
-            .client
-            .clone()
-            .database(UO_INDEXER_DB)
-            .collection::<MetaData>(LATEST_BLOCK_NUMBER);
+    async fn get_last_block_hash(&self) -> Result<Option<H256>, UoError> {
+        let collection = self.db().collection::<MetaData>(LATEST_BLOCK_NUMBER);
+        Ok(collection
+            .find_one(doc! {"key": DOC_KEY_INDEX}, None)
+            .await?
+            .and_then(|m| m.latest_block_hash))
+    }
+
+    async fn commit(
+        &self,
+        uos: Vec<UserOperationData>,
+        events: Vec<EntryPointEventData>,
+        last_block: u64,
+        last_hash: H256,
+    ) -> Result<(), UoError> {
+        let db = self.db();
+        let documents = uos
+            .iter()
+            .map(user_operation_document)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut session = self.start_transaction().await?;
+        let upsert = ReplaceOptions::builder().upsert(true).build();
+        let result = async {
+            let collection = db.collection::<Document>(UO_COLLECTION);
+            for (uo, document) in uos.iter().zip(documents) {
+                collection
+                    .replace_one_with_session(
+                        doc! {"chain_id": chain_id_bson(uo.chain_id), "uo_hash": format!("{:?}", uo.uo_hash)},
+                        document,
+                        upsert.clone(),
+                        &mut session,
+                    )
+                    .await?;
+            }
+            let collection = db.collection::<EntryPointEventData>(EVENT_COLLECTION);
+            for event in events {
+                let filter = doc! {
+                    "chain_id": chain_id_bson(event.chain_id),
+                    "transaction_hash": format!("{:?}", event.transaction_hash),
+                    "log_index": <i64>::try_from(event.log_index).expect("We are far from limitation"),
+                };
+                collection
+                    .replace_one_with_session(filter, &event, upsert.clone(), &mut session)
+                    .await?;
+            }
+            write_last_block(&db, last_block, Some(last_hash), &mut session).await
+        }
+        .await;
+        finish_transaction(session, result).await
+    }
+
+    async fn rollback(&self, block_number: u64, hash: Option<H256>) -> Result<(), UoError> {
+        let db = self.db();
         let number = <i64>::try_from(block_number).expect("We are far from limitation");
-        collection
-            .update_one(
-                doc! {"key": DOC_KEY_INDEX},
-                doc! {"$set":{ "latest_block_number": number}},
-                Some(UpdateOptions::builder().upsert(true).build()),
+        let mut session = self.start_transaction().await?;
+        let result = async {
+            db.collection::<UserOperationData>(UO_COLLECTION)
+                .delete_many_with_session(
+                    doc! {"block_number": {"$gt": number}},
+                    None,
+                    &mut session,
+                )
+                .await?;
+            db.collection::<EntryPointEventData>(EVENT_COLLECTION)
+                .delete_many_with_session(
+                    doc! {"block_number": {"$gt": number}},
+                    None,
+                    &mut session,
+                )
+                .await?;
+            write_last_block(&db, block_number, hash, &mut session).await
+        }
+        .await;
+        finish_transaction(session, result).await
+    }
+
+    async fn flush(&self) -> Result<(), UoError> {
+        // Every write is acknowledged by the server already.
+        Ok(())
+    }
+
+    async fn get_metadata(&self) -> Result<Option<StoreMetadata>, UoError> {
+        Ok(self
+            .db()
+            .collection::<StoreMetadata>(STORE_METADATA)
+            .find_one(None, None)
+            .await?)
+    }
+
+    async fn put_metadata(&self, metadata: &StoreMetadata) -> Result<(), UoError> {
+        self.db()
+            .collection::<StoreMetadata>(STORE_METADATA)
+            .replace_one(
+                doc! {},
+                metadata,
+                ReplaceOptions::builder().upsert(true).build(),
             )
             .await?;
         Ok(())
     }
 
-    async fn write_user_operation(
+    async fn get_user_operation(
         &self,
-        uos: Vec<crate::uo::UserOperationData>,
-    ) -> Result<(), UoError> {
-        if !uos.is_empty() {
-            let collection = self
-                .client
-                .clone()
-                .database(UO_INDEXER_DB)
-                .collection::<UserOperationData>(UO_COLLECTION);
-            collection.insert_many(uos, None).await?;
+        uo_hash: H256,
+    ) -> Result<Option<UserOperationData>, UoError> {
+        Ok(self
+            .db()
+            .collection::<UserOperationData>(UO_COLLECTION)
+            .find_one(doc! {"uo_hash": format!("{uo_hash:?}")}, None)
+            .await?)
+    }
+
+    async fn list_user_operations(
+        &self,
+        filter: &UoFilter,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page<UserOperationData>, UoError> {
+        let mut query = filter_document(filter);
+        if let Some(cursor) = after {
+            query = doc! {"$and": [query, after_document(cursor)]};
         }
-        Ok(())
+        let options = FindOptions::builder()
+            .sort(doc! {"block_number": 1, "log_index": 1})
+            .limit(<i64>::try_from(limit + 1).unwrap_or(i64::MAX))
+            .build();
+        let uos = self
+            .db()
+            .collection::<UserOperationData>(UO_COLLECTION)
+            .find(query, options)
+            .await?
+            .try_collect()
+            .await?;
+        Ok(Page::from_sorted(uos, limit))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ethers::types::U256;
+    use mongodb::bson::Bson;
+
+    use super::{numeric_document, user_operation_document, NUMERIC};
+    use crate::test_utils::user_operation_data;
+
+    #[test]
+    fn numeric_fields() {
+        let mut uo = user_operation_data(0, 10);
+        uo.uo.nonce = U256::MAX;
+        let document = user_operation_document(&uo).unwrap();
+        assert_eq!(
+            document.get_str("uo_hash").unwrap(),
+            format!("{:?}", uo.uo_hash)
+        );
+
+        let numeric = document.get_document(NUMERIC).unwrap();
+        let decimal = |field| numeric.get_decimal128(field).unwrap().to_string();
+        assert_eq!(decimal("actual_gas_cost"), "21000");
+        assert_eq!(decimal("nonce_sequence"), u64::MAX.to_string());
+        // The 58 digits of the key don't fit a Decimal128, the hex string holds it alone.
+        assert_eq!(numeric.get("nonce_key"), Some(&Bson::Null));
+
+        uo.uo.nonce = (U256::from(7) << 64) + 3;
+        let numeric = numeric_document(&uo);
+        assert_eq!(
+            numeric.get_decimal128("nonce_key").unwrap().to_string(),
+            "7"
+        );
+        assert_eq!(
+            numeric
+                .get_decimal128("nonce_sequence")
+                .unwrap()
+                .to_string(),
+            "3"
+        );
     }
 }
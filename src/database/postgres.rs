@@ -0,0 +1,668 @@
+use async_trait::async_trait;
+use ethers::types::{Address, Bytes, H256, U256};
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tokio_postgres::{types::ToSql, Client, NoTls, Row, Transaction};
+use tracing::{info, warn};
+
+use crate::uo::{EntryPointEventData, EntryPointVersion, UserOperation, UserOperationData};
+
+use super::{Cursor, DataBase, Page, StoreMetadata, UoError, UoFilter};
+
+/// Schema used when none is given, the one Postgres puts tables in by default.
+pub const DEFAULT_SCHEMA: &str = "public";
+
+/// Schema migrations in order, the version of a migration is its position starting at 1.
+const MIGRATIONS: [&str; 1] = [include_str!("migrations/0001_init.sql")];
+
+/// User operations joined with their bundle. Numbers are read as text, `NUMERIC` has no Rust
+/// type here.
+const SELECT_USER_OPERATIONS: &str = "SELECT u.chain_id, u.uo_hash, u.entry_point, \
+    u.entry_point_version, u.sender, u.nonce::TEXT AS nonce, u.init_code, u.call_data, \
+    u.call_gas_limit::TEXT AS call_gas_limit, \
+    u.verification_gas_limit::TEXT AS verification_gas_limit, \
+    u.pre_verification_gas::TEXT AS pre_verification_gas, \
+    u.max_fee_per_gas::TEXT AS max_fee_per_gas, \
+    u.max_priority_fee_per_gas::TEXT AS max_priority_fee_per_gas, \
+    u.paymaster_and_data, u.signature, u.paymaster, u.factory, u.success, \
+    u.actual_gas_cost::TEXT AS actual_gas_cost, u.actual_gas_price::TEXT AS actual_gas_price, \
+    u.transaction_hash, b.transaction_index, u.log_index, u.block_number, b.block_hash, \
+    u.aggregator, u.aggregated_signature \
+    FROM user_operations u \
+    JOIN bundles b ON b.chain_id = u.chain_id AND b.transaction_hash = u.transaction_hash";
+
+const UPSERT_BUNDLE: &str = "INSERT INTO bundles \
+    (chain_id, transaction_hash, transaction_index, block_number, block_hash) \
+    VALUES ($1, $2, $3, $4, $5) \
+    ON CONFLICT (chain_id, transaction_hash) DO UPDATE SET \
+    transaction_index = EXCLUDED.transaction_index, block_number = EXCLUDED.block_number, \
+    block_hash = EXCLUDED.block_hash";
+
+/// Numbers are passed as text and cast, `NUMERIC` has no Rust type here.
+const UPSERT_USER_OPERATION: &str = "INSERT INTO user_operations \
+    (chain_id, uo_hash, entry_point, entry_point_version, sender, nonce, init_code, call_data, \
+    call_gas_limit, verification_gas_limit, pre_verification_gas, max_fee_per_gas, \
+    max_priority_fee_per_gas, paymaster_and_data, signature, paymaster, factory, success, \
+    actual_gas_cost, actual_gas_price, transaction_hash, log_index, block_number, aggregator, \
+    aggregated_signature) \
+    VALUES ($1, $2, $3, $4, $5, $6::TEXT::NUMERIC, $7, $8, $9::TEXT::NUMERIC, \
+    $10::TEXT::NUMERIC, $11::TEXT::NUMERIC, $12::TEXT::NUMERIC, $13::TEXT::NUMERIC, $14, $15, \
+    $16, $17, $18, $19::TEXT::NUMERIC, $20::TEXT::NUMERIC, $21, $22, $23, $24, $25) \
+    ON CONFLICT (chain_id, uo_hash) DO UPDATE SET \
+    entry_point = EXCLUDED.entry_point, entry_point_version = EXCLUDED.entry_point_version, \
+    sender = EXCLUDED.sender, nonce = EXCLUDED.nonce, init_code = EXCLUDED.init_code, \
+    call_data = EXCLUDED.call_data, call_gas_limit = EXCLUDED.call_gas_limit, \
+    verification_gas_limit = EXCLUDED.verification_gas_limit, \
+    pre_verification_gas = EXCLUDED.pre_verification_gas, \
+    max_fee_per_gas = EXCLUDED.max_fee_per_gas, \
+    max_priority_fee_per_gas = EXCLUDED.max_priority_fee_per_gas, \
+    paymaster_and_data = EXCLUDED.paymaster_and_data, signature = EXCLUDED.signature, \
+    paymaster = EXCLUDED.paymaster, factory = EXCLUDED.factory, success = EXCLUDED.success, \
+    actual_gas_cost = EXCLUDED.actual_gas_cost, actual_gas_price = EXCLUDED.actual_gas_price, \
+    transaction_hash = EXCLUDED.transaction_hash, log_index = EXCLUDED.log_index, \
+    block_number = EXCLUDED.block_number, aggregator = EXCLUDED.aggregator, \
+    aggregated_signature = EXCLUDED.aggregated_signature";
+
+/// Numeric event arguments, stored in their own column instead of the json arguments, as
+/// `(column, argument)`.
+const NUMERIC_ARGS: [(&str, &str); 6] = [
+    ("nonce", "nonce"),
+    ("total_deposit", "totalDeposit"),
+    ("amount", "amount"),
+    ("total_staked", "totalStaked"),
+    ("unstake_delay_sec", "unstakeDelaySec"),
+    ("withdraw_time", "withdrawTime"),
+];
+
+/// Numbers are passed as text and cast like for user operations, in the order of `NUMERIC_ARGS`.
+const UPSERT_EVENT: &str = "INSERT INTO events \
+    (chain_id, transaction_hash, log_index, entry_point, event, args, block_number, nonce, \
+    total_deposit, amount, total_staked, unstake_delay_sec, withdraw_time) \
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8::TEXT::NUMERIC, $9::TEXT::NUMERIC, \
+    $10::TEXT::NUMERIC, $11::TEXT::NUMERIC, $12::TEXT::NUMERIC, $13::TEXT::NUMERIC) \
+    ON CONFLICT (chain_id, transaction_hash, log_index) DO UPDATE SET \
+    entry_point = EXCLUDED.entry_point, event = EXCLUDED.event, args = EXCLUDED.args, \
+    block_number = EXCLUDED.block_number, nonce = EXCLUDED.nonce, \
+    total_deposit = EXCLUDED.total_deposit, amount = EXCLUDED.amount, \
+    total_staked = EXCLUDED.total_staked, unstake_delay_sec = EXCLUDED.unstake_delay_sec, \
+    withdraw_time = EXCLUDED.withdraw_time";
+
+const UPSERT_CHECKPOINT: &str = "INSERT INTO checkpoint (last_block, last_hash) VALUES ($1, $2) \
+    ON CONFLICT (singleton) DO UPDATE SET last_block = EXCLUDED.last_block, \
+    last_hash = EXCLUDED.last_hash";
+
+/// Stores user operations, their bundles and events in normalized tables, see the migrations.
+/// Every commit and rollback runs in one transaction with the checkpoint.
+///
+/// The connection doesn't use TLS, servers requiring it refuse to connect.
+///
+/// The tables live in the given schema, so several chains can share a database with one schema
+/// each like the namespaces of `MongoDB`.
+pub struct Postgres {
+    client: Mutex<Client>,
+}
+
+impl Postgres {
+    pub async fn new(url: &str, schema: &str) -> Result<Self, UoError> {
+        let (mut client, connection) = tokio_postgres::connect(url, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!("Postgres connection closed: {e}");
+            }
+        });
+        let schema = format!("\"{}\"", schema.replace('"', "\"\""));
+        client
+            .batch_execute(&format!(
+                "CREATE SCHEMA IF NOT EXISTS {schema}; SET search_path TO {schema}"
+            ))
+            .await?;
+        migrate(&mut client).await?;
+        Ok(Self {
+            client: Mutex::new(client),
+        })
+    }
+}
+
+/// Apply the migrations the schema has not seen yet, all in one transaction.
+async fn migrate(client: &mut Client) -> Result<(), UoError> {
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (\
+            version INTEGER PRIMARY KEY, applied_at TIMESTAMPTZ NOT NULL DEFAULT now())",
+        )
+        .await?;
+    let transaction = client.transaction().await?;
+    // Indexers starting at the same time wait for each other here.
+    transaction
+        .batch_execute("LOCK TABLE schema_migrations IN EXCLUSIVE MODE")
+        .await?;
+    let applied: i32 = transaction
+        .query_one(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+            &[],
+        )
+        .await?
+        .get(0);
+    for (version, migration) in (1..).zip(MIGRATIONS).skip(applied as usize) {
+        transaction.batch_execute(migration).await?;
+        transaction
+            .execute(
+                "INSERT INTO schema_migrations (version) VALUES ($1)",
+                &[&version],
+            )
+            .await?;
+        info!("Applied Postgres migration {version}");
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+fn int(number: u64) -> i64 {
+    <i64>::try_from(number).expect("We are far from limitation")
+}
+
+fn version_name(version: EntryPointVersion) -> String {
+    match serde_json::to_value(version) {
+        Ok(Value::String(name)) => name,
+        _ => unreachable!("EntryPoint versions serialize to strings"),
+    }
+}
+
+fn decode_error(column: &str, e: impl std::fmt::Display) -> UoError {
+    UoError(format!("Could not decode {column}: {e}"))
+}
+
+fn u256(row: &Row, column: &str) -> Result<U256, UoError> {
+    U256::from_dec_str(row.try_get(column)?).map_err(|e| decode_error(column, e))
+}
+
+fn address(row: &Row, column: &str) -> Result<Address, UoError> {
+    let bytes: &[u8] = row.try_get(column)?;
+    if bytes.len() != Address::len_bytes() {
+        return Err(decode_error(column, "not an address"));
+    }
+    Ok(Address::from_slice(bytes))
+}
+
+fn optional_address(row: &Row, column: &str) -> Result<Option<Address>, UoError> {
+    match row.try_get::<_, Option<&[u8]>>(column)? {
+        Some(_) => address(row, column).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn hash(row: &Row, column: &str) -> Result<H256, UoError> {
+    let bytes: &[u8] = row.try_get(column)?;
+    if bytes.len() != H256::len_bytes() {
+        return Err(decode_error(column, "not a hash"));
+    }
+    Ok(H256::from_slice(bytes))
+}
+
+fn bytes(row: &Row, column: &str) -> Result<Bytes, UoError> {
+    Ok(Bytes::from(row.try_get::<_, Vec<u8>>(column)?))
+}
+
+fn number(row: &Row, column: &str) -> Result<u64, UoError> {
+    let number: i64 = row.try_get(column)?;
+    u64::try_from(number).map_err(|e| decode_error(column, e))
+}
+
+fn user_operation(row: &Row) -> Result<UserOperationData, UoError> {
+    let version = row.try_get::<_, String>("entry_point_version")?;
+    Ok(UserOperationData {
+        chain_id: number(row, "chain_id")?,
+        entry_point: address(row, "entry_point")?,
+        entry_point_version: serde_json::from_value(Value::String(version))
+            .map_err(|e| decode_error("entry_point_version", e))?,
+        uo: UserOperation {
+            sender: address(row, "sender")?,
+            nonce: u256(row, "nonce")?,
+            init_code: bytes(row, "init_code")?,
+            call_data: bytes(row, "call_data")?,
+            call_gas_limit: u256(row, "call_gas_limit")?,
+            verification_gas_limit: u256(row, "verification_gas_limit")?,
+            pre_verification_gas: u256(row, "pre_verification_gas")?,
+            max_fee_per_gas: u256(row, "max_fee_per_gas")?,
+            max_priority_fee_per_gas: u256(row, "max_priority_fee_per_gas")?,
+            paymaster_and_data: bytes(row, "paymaster_and_data")?,
+            signature: bytes(row, "signature")?,
+        },
+        uo_hash: hash(row, "uo_hash")?,
+        paymaster: address(row, "paymaster")?,
+        factory: optional_address(row, "factory")?,
+        success: row.try_get("success")?,
+        actual_gas_cost: u256(row, "actual_gas_cost")?,
+        actual_gas_price: u256(row, "actual_gas_price")?,
+        transaction_hash: hash(row, "transaction_hash")?,
+        transaction_index: number(row, "transaction_index")?,
+        log_index: number(row, "log_index")?,
+        block_number: number(row, "block_number")?,
+        block_hash: hash(row, "block_hash")?,
+        aggregator: optional_address(row, "aggregator")?,
+        aggregated_signature: row
+            .try_get::<_, Option<Vec<u8>>>("aggregated_signature")?
+            .map(Bytes::from),
+    })
+}
+
+async fn write_user_operation(
+    transaction: &Transaction<'_>,
+    uo: &UserOperationData,
+) -> Result<(), UoError> {
+    transaction
+        .execute(
+            UPSERT_BUNDLE,
+            &[
+                &int(uo.chain_id),
+                &uo.transaction_hash.as_bytes(),
+                &int(uo.transaction_index),
+                &int(uo.block_number),
+                &uo.block_hash.as_bytes(),
+            ],
+        )
+        .await?;
+    transaction
+        .execute(
+            UPSERT_USER_OPERATION,
+            &[
+                &int(uo.chain_id),
+                &uo.uo_hash.as_bytes(),
+                &uo.entry_point.as_bytes(),
+                &version_name(uo.entry_point_version),
+                &uo.uo.sender.as_bytes(),
+                &uo.uo.nonce.to_string(),
+                &uo.uo.init_code.as_ref(),
+                &uo.uo.call_data.as_ref(),
+                &uo.uo.call_gas_limit.to_string(),
+                &uo.uo.verification_gas_limit.to_string(),
+                &uo.uo.pre_verification_gas.to_string(),
+                &uo.uo.max_fee_per_gas.to_string(),
+                &uo.uo.max_priority_fee_per_gas.to_string(),
+                &uo.uo.paymaster_and_data.as_ref(),
+                &uo.uo.signature.as_ref(),
+                &uo.paymaster.as_bytes(),
+                &uo.factory.as_ref().map(Address::as_bytes),
+                &uo.success,
+                &uo.actual_gas_cost.to_string(),
+                &uo.actual_gas_price.to_string(),
+                &uo.transaction_hash.as_bytes(),
+                &int(uo.log_index),
+                &int(uo.block_number),
+                &uo.aggregator.as_ref().map(Address::as_bytes),
+                &uo.aggregated_signature.as_ref().map(Bytes::as_ref),
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+async fn write_event(
+    transaction: &Transaction<'_>,
+    event: &EntryPointEventData,
+) -> Result<(), UoError> {
+    // Events serialize as {"event": <name>, "args": <arguments>}.
+    let mut value = serde_json::to_value(&event.event).map_err(|e| UoError(e.to_string()))?;
+    let name = value["event"].as_str().unwrap_or_default().to_string();
+    let mut args = value["args"].take();
+    let mut numbers = Vec::with_capacity(NUMERIC_ARGS.len());
+    for (column, arg) in NUMERIC_ARGS {
+        let number = match args.as_object_mut().and_then(|args| args.remove(arg)) {
+            Some(number) => Some(
+                serde_json::from_value::<U256>(number)
+                    .map_err(|e| UoError(format!("Could not encode {column}: {e}")))?
+                    .to_string(),
+            ),
+            None => None,
+        };
+        numbers.push(number);
+    }
+    transaction
+        .execute(
+            UPSERT_BUNDLE,
+            &[
+                &int(event.chain_id),
+                &event.transaction_hash.as_bytes(),
+                &int(event.transaction_index),
+                &int(event.block_number),
+                &event.block_hash.as_bytes(),
+            ],
+        )
+        .await?;
+    transaction
+        .execute(
+            UPSERT_EVENT,
+            &[
+                &int(event.chain_id),
+                &event.transaction_hash.as_bytes(),
+                &int(event.log_index),
+                &event.entry_point.as_bytes(),
+                &name,
+                &args,
+                &int(event.block_number),
+                &numbers[0],
+                &numbers[1],
+                &numbers[2],
+                &numbers[3],
+                &numbers[4],
+                &numbers[5],
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+#[async_trait]
+impl DataBase for Postgres {
+    async fn get_last_block(&self) -> Result<u64, UoError> {
+        let client = self.client.lock().await;
+        match client
+            .query_opt("SELECT last_block FROM checkpoint", &[])
+            .await?
+        {
+            Some(row) => number(&row, "last_block"),
+            None => Ok(0),
+        }
+    }
+
+    async fn get_last_block_hash(&self) -> Result<Option<H256>, UoError> {
+        let client = self.client.lock().await;
+        client
+            .query_opt(
+                "SELECT last_hash FROM checkpoint WHERE last_hash IS NOT NULL",
+                &[],
+            )
+            .await?
+            .map(|row| hash(&row, "last_hash"))
+            .transpose()
+    }
+
+    async fn commit(
+        &self,
+        uos: Vec<UserOperationData>,
+        events: Vec<EntryPointEventData>,
+        last_block: u64,
+        last_hash: H256,
+    ) -> Result<(), UoError> {
+        let mut client = self.client.lock().await;
+        // Dropped without a commit, the transaction is rolled back.
+        let transaction = client.transaction().await?;
+        for uo in &uos {
+            write_user_operation(&transaction, uo).await?;
+        }
+        for event in &events {
+            write_event(&transaction, event).await?;
+        }
+        transaction
+            .execute(
+                UPSERT_CHECKPOINT,
+                &[&int(last_block), &Some(last_hash.as_bytes())],
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn rollback(&self, block_number: u64, hash: Option<H256>) -> Result<(), UoError> {
+        let mut client = self.client.lock().await;
+        let transaction = client.transaction().await?;
+        // User operations and events are deleted along with their bundle.
+        transaction
+            .execute(
+                "DELETE FROM bundles WHERE block_number > $1",
+                &[&int(block_number)],
+            )
+            .await?;
+        transaction
+            .execute(
+                UPSERT_CHECKPOINT,
+                &[&int(block_number), &hash.as_ref().map(H256::as_bytes)],
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), UoError> {
+        // Every commit is durable once the transaction returns.
+        Ok(())
+    }
+
+    async fn get_metadata(&self) -> Result<Option<StoreMetadata>, UoError> {
+        let client = self.client.lock().await;
+        let Some(row) = client
+            .query_opt(
+                "SELECT chain_id, entry_points, schema_version FROM store_metadata",
+                &[],
+            )
+            .await?
+        else {
+            return Ok(None);
+        };
+        let entry_points: Vec<Vec<u8>> = row.try_get("entry_points")?;
+        let schema_version: i32 = row.try_get("schema_version")?;
+        Ok(Some(StoreMetadata {
+            chain_id: number(&row, "chain_id")?,
+            entry_points: entry_points
+                .iter()
+                .map(|address| Address::from_slice(address))
+                .collect(),
+            schema_version: u32::try_from(schema_version)
+                .map_err(|e| decode_error("schema_version", e))?,
+            layout: None,
+        }))
+    }
+
+    async fn put_metadata(&self, metadata: &StoreMetadata) -> Result<(), UoError> {
+        let entry_points: Vec<&[u8]> = metadata
+            .entry_points
+            .iter()
+            .map(Address::as_bytes)
+            .collect();
+        let schema_version = <i32>::try_from(metadata.schema_version)
+            .map_err(|e| UoError(format!("Schema version too large: {e}")))?;
+        let client = self.client.lock().await;
+        client
+            .execute(
+                "INSERT INTO store_metadata (chain_id, entry_points, schema_version) \
+                VALUES ($1, $2, $3) ON CONFLICT (singleton) DO UPDATE SET \
+                chain_id = EXCLUDED.chain_id, entry_points = EXCLUDED.entry_points, \
+                schema_version = EXCLUDED.schema_version",
+                &[&int(metadata.chain_id), &entry_points, &schema_version],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn get_user_operation(
+        &self,
+        uo_hash: H256,
+    ) -> Result<Option<UserOperationData>, UoError> {
+        let client = self.client.lock().await;
+        let query = format!("{SELECT_USER_OPERATIONS} WHERE u.uo_hash = $1 LIMIT 1");
+        client
+            .query_opt(&query, &[&uo_hash.as_bytes()])
+            .await?
+            .map(|row| user_operation(&row))
+            .transpose()
+    }
+
+    async fn list_user_operations(
+        &self,
+        filter: &UoFilter,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page<UserOperationData>, UoError> {
+        let mut params: Vec<Box<dyn ToSql + Sync + Send>> = Vec::new();
+        let mut param = |value: Box<dyn ToSql + Sync + Send>| {
+            params.push(value);
+            format!("${}", params.len())
+        };
+        let mut conditions = vec![match *filter {
+            UoFilter::Sender(sender) => {
+                format!("u.sender = {}", param(Box::new(sender.as_bytes().to_vec())))
+            }
+            UoFilter::TransactionHash(hash) => format!(
+                "u.transaction_hash = {}",
+                param(Box::new(hash.as_bytes().to_vec()))
+            ),
+            UoFilter::Blocks { from, to } => format!(
+                "u.block_number BETWEEN {} AND {}",
+                param(Box::new(int(from))),
+                param(Box::new(int(to)))
+            ),
+            UoFilter::Paymaster(paymaster) => format!(
+                "u.paymaster = {}",
+                param(Box::new(paymaster.as_bytes().to_vec()))
+            ),
+            UoFilter::Factory(factory) => {
+                format!(
+                    "u.factory = {}",
+                    param(Box::new(factory.as_bytes().to_vec()))
+                )
+            }
+        }];
+        if let Some(cursor) = after {
+            conditions.push(format!(
+                "(u.block_number, u.log_index) > ({}, {})",
+                param(Box::new(int(cursor.block_number))),
+                param(Box::new(int(cursor.log_index)))
+            ));
+        }
+        let limit_param = param(Box::new(<i64>::try_from(limit + 1).unwrap_or(i64::MAX)));
+        let query = format!(
+            "{SELECT_USER_OPERATIONS} WHERE {} ORDER BY u.block_number, u.log_index LIMIT {limit_param}",
+            conditions.join(" AND ")
+        );
+        let params: Vec<&(dyn ToSql + Sync)> = params
+            .iter()
+            .map(|param| param.as_ref() as &(dyn ToSql + Sync))
+            .collect();
+
+        let client = self.client.lock().await;
+        let uos = client
+            .query(&query, &params)
+            .await?
+            .iter()
+            .map(user_operation)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Page::from_sorted(uos, limit))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ethers::types::{Address, H256, U256};
+    use tokio_postgres::NoTls;
+
+    use super::Postgres;
+    use crate::{
+        database::{Cursor, DataBase, StoreMetadata, UoFilter},
+        test_utils::{block_hash, user_operation_data},
+        uo::{DepositedEvent, EntryPointEvent, EntryPointEventData},
+    };
+
+    /// Set to a Postgres url to run the ignored tests, with `cargo test -- --ignored`. They work
+    /// in their own `uoindexer_test` schema.
+    const URL_VARIABLE: &str = "UOINDEXER_POSTGRES_URL";
+    const SCHEMA: &str = "uoindexer_test";
+
+    async fn store() -> Postgres {
+        let url = std::env::var(URL_VARIABLE)
+            .unwrap_or_else(|_| panic!("{URL_VARIABLE} must point at a Postgres database"));
+        let (client, connection) = tokio_postgres::connect(&url, NoTls).await.unwrap();
+        tokio::spawn(connection);
+        client
+            .batch_execute(&format!("DROP SCHEMA IF EXISTS {SCHEMA} CASCADE"))
+            .await
+            .unwrap();
+        // Opening twice applies the migrations once.
+        Postgres::new(&url, SCHEMA).await.unwrap();
+        Postgres::new(&url, SCHEMA).await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in UOINDEXER_POSTGRES_URL"]
+    async fn commit_query_and_rollback() {
+        let db = store().await;
+        assert_eq!(db.get_last_block().await.unwrap(), 0);
+        let metadata = StoreMetadata::new(1, vec![Address::from_low_u64_be(1)]);
+        db.put_metadata(&metadata).await.unwrap();
+        assert_eq!(db.get_metadata().await.unwrap(), Some(metadata));
+
+        let mut big = user_operation_data(1, 20);
+        big.uo.nonce = U256::MAX;
+        big.factory = Some(Address::from_low_u64_be(9));
+        let deposit = EntryPointEventData {
+            chain_id: 1,
+            entry_point: Address::from_low_u64_be(1),
+            event: EntryPointEvent::Deposited(DepositedEvent {
+                account: Address::from_low_u64_be(2),
+                total_deposit: U256::from(10),
+            }),
+            transaction_hash: H256::from_low_u64_be(0x2000 + 20),
+            transaction_index: 0,
+            log_index: 5,
+            block_number: 20,
+            block_hash: H256::from_low_u64_be(0x3000 + 20),
+        };
+        db.commit(
+            vec![user_operation_data(0, 10), big],
+            vec![deposit],
+            20,
+            block_hash(20),
+        )
+        .await
+        .unwrap();
+        // Committing an overlapping range again updates the stored rows.
+        db.commit(vec![user_operation_data(0, 10)], vec![], 20, block_hash(20))
+            .await
+            .unwrap();
+        assert_eq!(db.get_last_block().await.unwrap(), 20);
+        assert_eq!(
+            db.get_last_block_hash().await.unwrap(),
+            Some(block_hash(20))
+        );
+
+        let stored = db
+            .get_user_operation(H256::from_low_u64_be(0x1001))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.uo.nonce, U256::MAX);
+        assert_eq!(stored.block_hash, H256::from_low_u64_be(0x3000 + 20));
+        assert_eq!(stored.factory, Some(Address::from_low_u64_be(9)));
+        let row = db
+            .client
+            .lock()
+            .await
+            .query_one("SELECT total_deposit::TEXT, args FROM events", &[])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, &str>(0), "10");
+        assert_eq!(
+            row.get::<_, serde_json::Value>(1),
+            serde_json::json!({"account": format!("{:?}", Address::from_low_u64_be(2))})
+        );
+
+        let sender = UoFilter::Sender(Address::from_low_u64_be(2));
+        let page = db.list_user_operations(&sender, None, 1).await.unwrap();
+        assert_eq!(page.items[0].block_number, 10);
+        assert_eq!(page.next, Some(Cursor::of(&page.items[0])));
+        let page = db
+            .list_user_operations(&sender, page.next, 1)
+            .await
+            .unwrap();
+        assert_eq!((page.items[0].block_number, page.next), (20, None));
+        let factory = UoFilter::Factory(Address::from_low_u64_be(9));
+        let page = db.list_user_operations(&factory, None, 10).await.unwrap();
+        assert_eq!(page.items.len(), 1);
+
+        db.rollback(15, None).await.unwrap();
+        assert_eq!(db.get_last_block_hash().await.unwrap(), None);
+        assert_eq!(db.get_last_block().await.unwrap(), 15);
+        let blocks = UoFilter::Blocks { from: 0, to: 100 };
+        let page = db.list_user_operations(&blocks, None, 10).await.unwrap();
+        assert_eq!(page.items.len(), 1);
+    }
+}
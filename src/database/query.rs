@@ -0,0 +1,146 @@
+use std::{fmt::Display, str::FromStr};
+
+use ethers::types::{Address, H256};
+
+use crate::uo::UserOperationData;
+
+/// Position of a user operation in the order lists are returned in, by block number and then log
+/// index. A page ends with the cursor of its last user operation, passing it back returns the
+/// user operations after it. Written as `<block number>-<log index>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+    pub block_number: u64,
+    pub log_index: u64,
+}
+
+impl Cursor {
+    pub fn of(uo: &UserOperationData) -> Self {
+        Self {
+            block_number: uo.block_number,
+            log_index: uo.log_index,
+        }
+    }
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.block_number, self.log_index)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (block_number, log_index) = value
+            .split_once('-')
+            .ok_or_else(|| "expected <block number>-<log index>".to_string())?;
+        Ok(Self {
+            block_number: block_number.parse().map_err(|e| format!("{e}"))?,
+            log_index: log_index.parse().map_err(|e| format!("{e}"))?,
+        })
+    }
+}
+
+/// Which user operations a list returns.
+#[derive(Clone, Copy, Debug)]
+pub enum UoFilter {
+    Sender(Address),
+    TransactionHash(H256),
+    /// Blocks `from` to `to`, both included.
+    Blocks {
+        from: u64,
+        to: u64,
+    },
+    Paymaster(Address),
+    /// Factory that deployed the sender, taken from the `init_code`.
+    Factory(Address),
+}
+
+impl UoFilter {
+    pub fn matches(&self, uo: &UserOperationData) -> bool {
+        match *self {
+            Self::Sender(sender) => uo.uo.sender == sender,
+            Self::TransactionHash(hash) => uo.transaction_hash == hash,
+            Self::Blocks { from, to } => (from..=to).contains(&uo.block_number),
+            Self::Paymaster(paymaster) => uo.paymaster == paymaster,
+            Self::Factory(factory) => uo.factory == Some(factory),
+        }
+    }
+}
+
+/// One page of a list, `next` is set when more items follow.
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<Cursor>,
+}
+
+impl Page<UserOperationData> {
+    /// Page of up to `limit` items out of `items`, which are sorted and were fetched with one
+    /// more than `limit` to tell whether more follow.
+    pub(crate) fn from_sorted(mut items: Vec<UserOperationData>, limit: usize) -> Self {
+        let next = if items.len() > limit {
+            items.truncate(limit);
+            items.last().map(Cursor::of)
+        } else {
+            None
+        };
+        Self { items, next }
+    }
+
+    /// Page of the user operations matching `filter` after `after`, for backends that scan
+    /// their records.
+    pub(crate) fn scan(
+        uos: impl IntoIterator<Item = UserOperationData>,
+        filter: &UoFilter,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Self {
+        let mut items: Vec<_> = uos
+            .into_iter()
+            .filter(|uo| filter.matches(uo) && after.is_none_or(|after| Cursor::of(uo) > after))
+            .collect();
+        items.sort_by_key(Cursor::of);
+        items.truncate(limit + 1);
+        Self::from_sorted(items, limit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cursor, Page, UoFilter};
+    use crate::test_utils::user_operation_data;
+
+    #[test]
+    fn parse_cursor() {
+        let cursor: Cursor = "120-7".parse().unwrap();
+        assert_eq!(
+            cursor,
+            Cursor {
+                block_number: 120,
+                log_index: 7
+            }
+        );
+        assert_eq!(cursor.to_string(), "120-7");
+        assert!("120".parse::<Cursor>().is_err());
+    }
+
+    #[test]
+    fn scan_pages() {
+        let uos = || {
+            (0..5)
+                .rev()
+                .map(|nonce| user_operation_data(nonce, 10 + nonce))
+        };
+        let filter = UoFilter::Blocks { from: 11, to: 20 };
+        let page = Page::scan(uos(), &filter, None, 2);
+        let blocks: Vec<_> = page.items.iter().map(|uo| uo.block_number).collect();
+        assert_eq!(blocks, [11, 12]);
+
+        let page = Page::scan(uos(), &filter, page.next, 2);
+        let blocks: Vec<_> = page.items.iter().map(|uo| uo.block_number).collect();
+        assert_eq!(blocks, [13, 14]);
+        assert_eq!(page.next, None);
+    }
+}
@@ -1,13 +1,51 @@
 use async_trait::async_trait;
-use rocksdb::{DBWithThreadMode, SingleThreaded, DB};
+use ethers::types::H256;
+use rocksdb::{
+    ColumnFamily, DBWithThreadMode, Direction, IteratorMode, SingleThreaded, WriteBatch, DB,
+};
 use std::path::PathBuf;
 
-use crate::uo::UserOperationData;
+use crate::uo::{EntryPointEventData, UserOperationData};
 
-use super::{DataBase, UoError};
+use super::{legacy_store, Cursor, DataBase, Page, StoreMetadata, UoError, UoFilter};
 
 const LAST_BLOCK_DB: &str = "lastBlock";
+/// Key of the store metadata, in the column family of the last block.
+const METADATA: &[u8] = b"metadata";
+/// Key of the hash of the last block, next to the last block.
+const LAST_BLOCK_HASH: &[u8] = b"lastBlockHash";
 const UO: &str = "UserOperation";
+const EVENT: &str = "EntryPointEvent";
+/// `block number ++ log index`, ending with the key of the event like the indexes of `UO`.
+const EVENT_BY_BLOCK: &str = "EntryPointEventByBlock";
+/// Secondary indexes of `UO`. Index keys are big endian, so they iterate in order, and end with
+/// the key of the user operation, which is also the value.
+const UO_BY_HASH: &str = "UserOperationByHash";
+/// `sender ++ nonce`
+const UO_BY_SENDER: &str = "UserOperationBySender";
+/// `block number ++ transaction index ++ log index`
+const UO_BY_BLOCK: &str = "UserOperationByBlock";
+/// `transaction hash ++ log index`
+const UO_BY_TRANSACTION: &str = "UserOperationByTransaction";
+/// `paymaster ++ block number ++ log index`
+const UO_BY_PAYMASTER: &str = "UserOperationByPaymaster";
+/// `sender ++ block number ++ log index`, the history of a sender in list order.
+const UO_BY_SENDER_BLOCK: &str = "UserOperationBySenderBlock";
+/// `factory ++ block number ++ log index`, only for user operations deploying their sender.
+const UO_BY_FACTORY: &str = "UserOperationByFactory";
+const COLUMN_FAMILIES: [&str; 11] = [
+    LAST_BLOCK_DB,
+    UO,
+    EVENT,
+    EVENT_BY_BLOCK,
+    UO_BY_HASH,
+    UO_BY_SENDER,
+    UO_BY_BLOCK,
+    UO_BY_TRANSACTION,
+    UO_BY_PAYMASTER,
+    UO_BY_SENDER_BLOCK,
+    UO_BY_FACTORY,
+];
 
 pub struct RocksDb {
     _db_path: PathBuf,
@@ -24,40 +62,222 @@ fn u64_to_u8_vec(num: u64) -> Vec<u8> {
     num.to_le_bytes().to_vec()
 }
 
+/// Entries of `uo` in every secondary index.
+fn index_entries(uo: &UserOperationData) -> Vec<(&'static str, Vec<u8>)> {
+    let key = uo.key();
+    let mut nonce = [0u8; 32];
+    uo.uo.nonce.to_big_endian(&mut nonce);
+    let position = [uo.block_number.to_be_bytes(), uo.log_index.to_be_bytes()].concat();
+    let entry = |parts: &[&[u8]]| [parts.concat(), key.as_bytes().to_vec()].concat();
+    let mut entries = vec![
+        (UO_BY_HASH, entry(&[uo.uo_hash.as_bytes()])),
+        (UO_BY_SENDER, entry(&[uo.uo.sender.as_bytes(), &nonce])),
+        (
+            UO_BY_BLOCK,
+            entry(&[
+                &uo.block_number.to_be_bytes(),
+                &uo.transaction_index.to_be_bytes(),
+                &uo.log_index.to_be_bytes(),
+            ]),
+        ),
+        (
+            UO_BY_TRANSACTION,
+            entry(&[uo.transaction_hash.as_bytes(), &uo.log_index.to_be_bytes()]),
+        ),
+        (
+            UO_BY_PAYMASTER,
+            entry(&[uo.paymaster.as_bytes(), &position]),
+        ),
+        (
+            UO_BY_SENDER_BLOCK,
+            entry(&[uo.uo.sender.as_bytes(), &position]),
+        ),
+    ];
+    if let Some(factory) = uo.factory {
+        entries.push((UO_BY_FACTORY, entry(&[factory.as_bytes(), &position])));
+    }
+    entries
+}
+
+fn event_index_entry(event: &EntryPointEventData) -> Vec<u8> {
+    [
+        &event.block_number.to_be_bytes()[..],
+        &event.log_index.to_be_bytes(),
+        event.key().as_bytes(),
+    ]
+    .concat()
+}
+
+fn decode_user_operation(value: &[u8]) -> Result<UserOperationData, UoError> {
+    serde_json::from_slice(value).map_err(|e| UoError(e.to_string()))
+}
+
 #[async_trait]
 impl DataBase for RocksDb {
     async fn get_last_block(&self) -> Result<u64, UoError> {
-        let cf = self
-            .instance
-            .cf_handle(LAST_BLOCK_DB)
-            .ok_or_else(|| UoError("Could not find lastBlock column family".to_string()))?;
+        let cf = self.cf(LAST_BLOCK_DB)?;
         match self.instance.get_cf(cf, b"lastBlock")? {
             Some(res) => Ok(u8_vec_to_u64(res)),
             None => Ok(0u64),
         }
     }
-    async fn write_user_operation(&self, uos: Vec<UserOperationData>) -> Result<(), UoError> {
-        let cf = self
+
+    async fn get_last_block_hash(&self) -> Result<Option<H256>, UoError> {
+        let cf = self.cf(LAST_BLOCK_DB)?;
+        Ok(self
             .instance
-            .cf_handle(UO)
-            .ok_or_else(|| UoError("Could not find lastBlock column family".to_string()))?;
+            .get_cf(cf, LAST_BLOCK_HASH)?
+            .map(|hash| H256::from_slice(&hash)))
+    }
+
+    async fn commit(
+        &self,
+        uos: Vec<UserOperationData>,
+        events: Vec<EntryPointEventData>,
+        last_block: u64,
+        last_hash: H256,
+    ) -> Result<(), UoError> {
+        let mut batch = WriteBatch::default();
         for uo in uos {
-            let j = serde_json::to_vec(&uo).map_err(|e| UoError(e.to_string()))?;
-            self.instance.put_cf(cf, uo.uo_hash.to_string(), j)?;
+            self.put_user_operation(&mut batch, &uo)?;
         }
+        let cf = self.cf(EVENT)?;
+        let by_block = self.cf(EVENT_BY_BLOCK)?;
+        for event in events {
+            let j = serde_json::to_vec(&event).map_err(|e| UoError(e.to_string()))?;
+            batch.put_cf(by_block, event_index_entry(&event), event.key());
+            batch.put_cf(cf, event.key(), j);
+        }
+        batch.put_cf(
+            self.cf(LAST_BLOCK_DB)?,
+            b"lastBlock",
+            u64_to_u8_vec(last_block),
+        );
+        batch.put_cf(self.cf(LAST_BLOCK_DB)?, LAST_BLOCK_HASH, last_hash);
+        self.instance.write(batch)?;
         self.instance.flush()?;
         Ok(())
     }
-    async fn write_last_block(&self, block_number: u64) -> Result<(), UoError> {
-        let cf = self
-            .instance
-            .cf_handle(LAST_BLOCK_DB)
-            .ok_or_else(|| UoError("Could not find lastBlock column family".to_string()))?;
+
+    async fn rollback(&self, block_number: u64, hash: Option<H256>) -> Result<(), UoError> {
+        let mut batch = WriteBatch::default();
+        let start = (block_number + 1).to_be_bytes();
+        let above = IteratorMode::From(&start, Direction::Forward);
+        for item in self.instance.iterator_cf(self.cf(UO_BY_BLOCK)?, above) {
+            let (_, key) = item?;
+            if let Some(uo) = self.user_operation(&key)? {
+                self.delete_user_operation(&mut batch, &uo)?;
+            }
+        }
+        let by_block = self.cf(EVENT_BY_BLOCK)?;
+        let above = IteratorMode::From(&start, Direction::Forward);
+        for item in self.instance.iterator_cf(by_block, above) {
+            let (entry, key) = item?;
+            batch.delete_cf(by_block, entry);
+            batch.delete_cf(self.cf(EVENT)?, key);
+        }
+        batch.put_cf(
+            self.cf(LAST_BLOCK_DB)?,
+            b"lastBlock",
+            u64_to_u8_vec(block_number),
+        );
+        match hash {
+            Some(hash) => batch.put_cf(self.cf(LAST_BLOCK_DB)?, LAST_BLOCK_HASH, hash),
+            None => batch.delete_cf(self.cf(LAST_BLOCK_DB)?, LAST_BLOCK_HASH),
+        }
+        self.instance.write(batch)?;
+        self.instance.flush()?;
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), UoError> {
+        self.instance.flush()?;
+        Ok(())
+    }
+
+    async fn get_metadata(&self) -> Result<Option<StoreMetadata>, UoError> {
+        self.instance
+            .get_cf(self.cf(LAST_BLOCK_DB)?, METADATA)?
+            .map(|value| serde_json::from_slice(&value).map_err(|e| UoError(e.to_string())))
+            .transpose()
+    }
+
+    async fn put_metadata(&self, metadata: &StoreMetadata) -> Result<(), UoError> {
+        let value = serde_json::to_vec(metadata).map_err(|e| UoError(e.to_string()))?;
         self.instance
-            .put_cf(cf, b"lastBlock", u64_to_u8_vec(block_number))?;
+            .put_cf(self.cf(LAST_BLOCK_DB)?, METADATA, value)?;
         self.instance.flush()?;
         Ok(())
     }
+
+    async fn get_user_operation(
+        &self,
+        uo_hash: H256,
+    ) -> Result<Option<UserOperationData>, UoError> {
+        match self.indexed(UO_BY_HASH, uo_hash.as_bytes())?.first() {
+            Some(key) => self.user_operation(key),
+            None => Ok(None),
+        }
+    }
+
+    async fn list_user_operations(
+        &self,
+        filter: &UoFilter,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page<UserOperationData>, UoError> {
+        let cursor = after.unwrap_or(Cursor {
+            block_number: 0,
+            log_index: 0,
+        });
+        let position = [
+            cursor.block_number.to_be_bytes(),
+            cursor.log_index.to_be_bytes(),
+        ]
+        .concat();
+        match *filter {
+            UoFilter::Sender(sender) => self.page_from_index(
+                UO_BY_SENDER_BLOCK,
+                sender.as_bytes(),
+                &position,
+                filter,
+                after,
+                limit,
+            ),
+            UoFilter::TransactionHash(hash) => self.page_from_index(
+                UO_BY_TRANSACTION,
+                hash.as_bytes(),
+                &cursor.log_index.to_be_bytes(),
+                filter,
+                after,
+                limit,
+            ),
+            UoFilter::Blocks { from, .. } => self.page_from_index(
+                UO_BY_BLOCK,
+                &[],
+                &from.max(cursor.block_number).to_be_bytes(),
+                filter,
+                after,
+                limit,
+            ),
+            UoFilter::Paymaster(paymaster) => self.page_from_index(
+                UO_BY_PAYMASTER,
+                paymaster.as_bytes(),
+                &position,
+                filter,
+                after,
+                limit,
+            ),
+            UoFilter::Factory(factory) => self.page_from_index(
+                UO_BY_FACTORY,
+                factory.as_bytes(),
+                &position,
+                filter,
+                after,
+                limit,
+            ),
+        }
+    }
 }
 
 impl RocksDb {
@@ -68,22 +288,294 @@ impl RocksDb {
         options.create_missing_column_families(true);
         let cfs = DB::list_cf(&options, path.clone()).unwrap_or(vec![]);
 
-        if !cfs.iter().any(|cf| cf.as_str() == LAST_BLOCK_DB) {
-            let mut instance = rocksdb::DB::open_cf(&options, path.clone(), cfs.clone())?;
-            let options = rocksdb::Options::default();
-            instance.create_cf(LAST_BLOCK_DB, &options)?;
-        }
-
-        if !cfs.iter().any(|cf| cf.as_str() == UO) {
+        let missing: Vec<&str> = COLUMN_FAMILIES
+            .into_iter()
+            .filter(|name| !cfs.iter().any(|cf| cf.as_str() == *name))
+            .collect();
+        if !missing.is_empty() {
             let mut instance = rocksdb::DB::open_cf(&options, path.clone(), cfs)?;
-
-            let options = rocksdb::Options::default();
-            instance.create_cf(UO, &options)?;
+            for name in missing {
+                let options = rocksdb::Options::default();
+                instance.create_cf(name, &options)?;
+            }
         }
-        let instance = rocksdb::DB::open_cf(&options, path.clone(), vec![LAST_BLOCK_DB, UO])?;
-        Ok(Self {
+        let instance = rocksdb::DB::open_cf(&options, path.clone(), COLUMN_FAMILIES)?;
+        let db = Self {
             _db_path: path,
             instance,
-        })
+        };
+        if db.has_legacy_records()? {
+            return Err(legacy_store(db._db_path.display()).into());
+        }
+        Ok(db)
+    }
+
+    /// Whether the store holds user operations of the first version, keyed by their abbreviated
+    /// uo hash like `0x1234…abcd` where the current keys start with the chain id.
+    fn has_legacy_records(&self) -> Result<bool, UoError> {
+        match self.instance.prefix_iterator_cf(self.cf(UO)?, b"0x").next() {
+            Some(item) => Ok(item?.0.starts_with(b"0x")),
+            None => Ok(false),
+        }
+    }
+
+    fn user_operation(&self, key: &[u8]) -> Result<Option<UserOperationData>, UoError> {
+        self.instance
+            .get_cf(self.cf(UO)?, key)?
+            .map(|value| decode_user_operation(&value))
+            .transpose()
+    }
+
+    /// Add `uo` and its index entries to `batch`, replacing the entries of a stored version.
+    fn put_user_operation(
+        &self,
+        batch: &mut WriteBatch,
+        uo: &UserOperationData,
+    ) -> Result<(), UoError> {
+        if let Some(stored) = self.user_operation(uo.key().as_bytes())? {
+            for (index, entry) in index_entries(&stored) {
+                batch.delete_cf(self.cf(index)?, entry);
+            }
+        }
+        let j = serde_json::to_vec(uo).map_err(|e| UoError(e.to_string()))?;
+        batch.put_cf(self.cf(UO)?, uo.key(), j);
+        for (index, entry) in index_entries(uo) {
+            batch.put_cf(self.cf(index)?, entry, uo.key());
+        }
+        Ok(())
+    }
+
+    fn delete_user_operation(
+        &self,
+        batch: &mut WriteBatch,
+        uo: &UserOperationData,
+    ) -> Result<(), UoError> {
+        batch.delete_cf(self.cf(UO)?, uo.key());
+        for (index, entry) in index_entries(uo) {
+            batch.delete_cf(self.cf(index)?, entry);
+        }
+        Ok(())
+    }
+
+    /// Keys of the user operations whose entry in `index` starts with `prefix`.
+    fn indexed(&self, index: &str, prefix: &[u8]) -> Result<Vec<Box<[u8]>>, UoError> {
+        let mut keys = Vec::new();
+        for item in self.instance.prefix_iterator_cf(self.cf(index)?, prefix) {
+            let (entry, key) = item?;
+            if !entry.starts_with(prefix) {
+                break;
+            }
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    /// Page of the user operations in the `index` column family from `prefix ++ start` on, while
+    /// the keys start with `prefix` and the user operations match `filter`.
+    fn page_from_index(
+        &self,
+        index: &str,
+        prefix: &[u8],
+        start: &[u8],
+        filter: &UoFilter,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page<UserOperationData>, UoError> {
+        let start = [prefix, start].concat();
+        let mode = IteratorMode::From(&start, Direction::Forward);
+        let mut uos = Vec::new();
+        for item in self.instance.iterator_cf(self.cf(index)?, mode) {
+            let (key, value) = item?;
+            if !key.starts_with(prefix) || uos.len() > limit {
+                break;
+            }
+            let Some(uo) = self.user_operation(&value)? else {
+                continue;
+            };
+            // Index keys are ordered like cursors, the first user operation outside of the
+            // filter ends the page.
+            if !filter.matches(&uo) {
+                break;
+            }
+            if after.is_none_or(|after| Cursor::of(&uo) > after) {
+                uos.push(uo);
+            }
+        }
+        Ok(Page::from_sorted(uos, limit))
+    }
+
+    fn cf(&self, name: &str) -> Result<&ColumnFamily, UoError> {
+        self.instance
+            .cf_handle(name)
+            .ok_or_else(|| UoError(format!("Could not find {name} column family")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ethers::types::{Address, H256};
+    use rocksdb::IteratorMode;
+
+    use super::{
+        RocksDb, EVENT, EVENT_BY_BLOCK, LAST_BLOCK_DB, UO, UO_BY_BLOCK, UO_BY_FACTORY, UO_BY_HASH,
+        UO_BY_PAYMASTER, UO_BY_SENDER, UO_BY_SENDER_BLOCK, UO_BY_TRANSACTION,
+    };
+    use crate::{
+        database::{Cursor, DataBase, UoFilter},
+        test_utils::{block_hash, temp_dir, user_operation_data},
+        uo::{BeforeExecutionEvent, EntryPointEvent, EntryPointEventData},
+    };
+
+    /// The `BeforeExecution` event of the bundle in block `block_number`.
+    fn before_execution(block_number: u64) -> EntryPointEventData {
+        EntryPointEventData {
+            chain_id: 1,
+            entry_point: Address::from_low_u64_be(1),
+            event: EntryPointEvent::BeforeExecution(BeforeExecutionEvent {}),
+            transaction_hash: H256::from_low_u64_be(0x2000 + block_number),
+            transaction_index: 0,
+            log_index: 0,
+            block_number,
+            block_hash: block_hash(block_number),
+        }
+    }
+
+    #[tokio::test]
+    async fn commit_and_rollback() {
+        let dir = temp_dir("rocksdb-commit");
+        let db = RocksDb::new(dir.clone()).unwrap();
+        db.commit(
+            vec![user_operation_data(0, 10), user_operation_data(1, 20)],
+            vec![before_execution(10), before_execution(20)],
+            20,
+            block_hash(20),
+        )
+        .await
+        .unwrap();
+        // Committing the same range again leaves the same state.
+        db.commit(
+            vec![user_operation_data(1, 20)],
+            vec![before_execution(20)],
+            20,
+            block_hash(20),
+        )
+        .await
+        .unwrap();
+        assert_eq!(db.get_last_block().await.unwrap(), 20);
+        assert_eq!(
+            db.get_last_block_hash().await.unwrap(),
+            Some(block_hash(20))
+        );
+
+        db.rollback(15, Some(block_hash(15))).await.unwrap();
+        assert_eq!(db.get_last_block().await.unwrap(), 15);
+        assert_eq!(
+            db.get_last_block_hash().await.unwrap(),
+            Some(block_hash(15))
+        );
+        for cf in [UO, EVENT, EVENT_BY_BLOCK] {
+            let stored = db
+                .instance
+                .iterator_cf(db.cf(cf).unwrap(), IteratorMode::Start)
+                .count();
+            assert_eq!(stored, 1, "{cf}");
+        }
+
+        let uo = db.get_user_operation(H256::from_low_u64_be(0x1000)).await;
+        assert_eq!(uo.unwrap().unwrap().block_number, 10);
+        let page = db
+            .list_user_operations(&UoFilter::Blocks { from: 0, to: 20 }, None, 10)
+            .await
+            .unwrap();
+        assert_eq!(page.items.len(), 1);
+        drop(db);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn secondary_indexes() {
+        let dir = temp_dir("rocksdb-indexes");
+        let db = RocksDb::new(dir.clone()).unwrap();
+        let paymaster = Address::from_low_u64_be(7);
+        let factory = Address::from_low_u64_be(9);
+        let uos = (0..6)
+            .map(|nonce| {
+                let mut uo = user_operation_data(nonce, 10 + nonce / 2);
+                if nonce % 2 == 0 {
+                    uo.paymaster = paymaster;
+                }
+                if nonce % 5 == 0 {
+                    uo.factory = Some(factory);
+                }
+                uo
+            })
+            .collect();
+        db.commit(uos, vec![], 12, block_hash(12)).await.unwrap();
+
+        let store = &db;
+        let list = |filter: UoFilter, after| async move {
+            store.list_user_operations(&filter, after, 2).await
+        };
+        let blocks = UoFilter::Blocks { from: 11, to: 12 };
+        let page = list(blocks, None).await.unwrap();
+        let nonces: Vec<_> = page.items.iter().map(|uo| uo.log_index).collect();
+        assert_eq!(nonces, [2, 3]);
+        let page = list(blocks, page.next).await.unwrap();
+        let nonces: Vec<_> = page.items.iter().map(|uo| uo.log_index).collect();
+        assert_eq!((nonces, page.next), (vec![4, 5], None));
+
+        let page = list(UoFilter::Paymaster(paymaster), None).await.unwrap();
+        let page = list(UoFilter::Paymaster(paymaster), page.next)
+            .await
+            .unwrap();
+        assert_eq!(page.items[0].log_index, 4);
+        let transaction = UoFilter::TransactionHash(H256::from_low_u64_be(0x2000 + 11));
+        assert_eq!(list(transaction, None).await.unwrap().items.len(), 2);
+        let sender = UoFilter::Sender(Address::from_low_u64_be(2));
+        let page = list(sender, Cursor::of(&user_operation_data(3, 11)).into())
+            .await
+            .unwrap();
+        assert_eq!(page.items[0].log_index, 4);
+        let page = list(UoFilter::Factory(factory), None).await.unwrap();
+        let nonces: Vec<_> = page.items.iter().map(|uo| uo.log_index).collect();
+        assert_eq!((nonces, page.next), (vec![0, 5], None));
+
+        // Rolled back user operations leave no index entries behind.
+        db.rollback(10, None).await.unwrap();
+        let indexes = [
+            (UO_BY_HASH, 2),
+            (UO_BY_SENDER, 2),
+            (UO_BY_BLOCK, 2),
+            (UO_BY_TRANSACTION, 2),
+            (UO_BY_PAYMASTER, 2),
+            (UO_BY_SENDER_BLOCK, 2),
+            (UO_BY_FACTORY, 1),
+        ];
+        for (index, expected) in indexes {
+            let cf = db.cf(index).unwrap();
+            let entries = db.instance.iterator_cf(cf, IteratorMode::Start).count();
+            assert_eq!(entries, expected, "{index}");
+        }
+        assert!(list(blocks, None).await.unwrap().items.is_empty());
+        drop(db);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuse_legacy_records() {
+        let dir = temp_dir("rocksdb-legacy");
+        let mut options = rocksdb::Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let instance = rocksdb::DB::open_cf(&options, dir.clone(), [LAST_BLOCK_DB, UO]).unwrap();
+        // The first version keyed user operations by the display form of their hash.
+        let uo = user_operation_data(0, 10);
+        let cf = instance.cf_handle(UO).unwrap();
+        instance.put_cf(cf, uo.uo_hash.to_string(), b"{}").unwrap();
+        drop(instance);
+
+        let error = RocksDb::new(dir.clone()).err().unwrap();
+        assert!(error.to_string().contains("new store"));
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
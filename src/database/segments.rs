@@ -0,0 +1,241 @@
+//! Append-only layout of `FileDB`: records are appended as json lines to segment files that
+//! each cover a fixed number of blocks, next to an `index.json` listing the segments.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{
+    filestore::{sync_dir, too_many_records, write_synced},
+    UoError,
+};
+use crate::uo::{EntryPointEventData, UserOperationData};
+
+pub const SEGMENT_FOLDER: &str = "segments";
+const INDEX_FILE: &str = "index.json";
+/// Lines a staged change appends, one file per segment.
+const APPEND_FOLDER: &str = "append";
+/// Length of every segment before the staged change, an interrupted append is cut back to it.
+const OFFSETS_FILE: &str = "offsets.json";
+const TMP_EXTENSION: &str = "tmp";
+const USER_OPERATIONS: &str = "user_operations";
+const EVENTS: &str = "events";
+
+/// One entry of `index.json`.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    pub file: String,
+    pub kind: String,
+    pub from_block: u64,
+    pub to_block: u64,
+}
+
+fn segment_name(kind: &str, block_number: u64, blocks: u64) -> String {
+    let from = block_number / blocks * blocks;
+    format!("{kind}-{from:012}-{:012}.jsonl", from + blocks - 1)
+}
+
+fn parse_segment_name(file: &str) -> Option<Segment> {
+    let stem = file.strip_suffix(".jsonl")?;
+    let mut parts = stem.rsplitn(3, '-');
+    let to_block = parts.next()?.parse().ok()?;
+    let from_block = parts.next()?.parse().ok()?;
+    let kind = parts.next()?.to_string();
+    Some(Segment {
+        file: file.to_string(),
+        kind,
+        from_block,
+        to_block,
+    })
+}
+
+/// Segments stored in `folder`, ordered by kind and block.
+fn segments(folder: &Path) -> Result<Vec<Segment>, UoError> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(folder.join(SEGMENT_FOLDER))? {
+        let name = entry?.file_name();
+        if let Some(segment) = name.to_str().and_then(parse_segment_name) {
+            segments.push(segment);
+        }
+    }
+    segments.sort_by(|a, b| a.file.cmp(&b.file));
+    Ok(segments)
+}
+
+fn block_of_line(line: &[u8]) -> Option<u64> {
+    serde_json::from_slice::<Value>(line).ok()?["block_number"].as_u64()
+}
+
+/// Write the lines the change appends into `staging`, along with the current segment lengths.
+pub fn stage(
+    folder: &Path,
+    staging: &Path,
+    uos: &[UserOperationData],
+    events: &[EntryPointEventData],
+    blocks: u64,
+) -> Result<(), UoError> {
+    let mut appends: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    let lines = uos
+        .iter()
+        .map(|uo| (USER_OPERATIONS, uo.block_number, serde_json::to_vec(uo)))
+        .chain(
+            events
+                .iter()
+                .map(|event| (EVENTS, event.block_number, serde_json::to_vec(event))),
+        );
+    for (kind, block_number, line) in lines {
+        let data = appends
+            .entry(segment_name(kind, block_number, blocks))
+            .or_default();
+        data.extend(line.map_err(|e| UoError(e.to_string()))?);
+        data.push(b'\n');
+    }
+
+    let append = staging.join(APPEND_FOLDER);
+    fs::create_dir_all(&append)?;
+    let mut offsets = BTreeMap::new();
+    for (name, data) in appends {
+        let length = fs::metadata(folder.join(SEGMENT_FOLDER).join(&name))
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        offsets.insert(name.clone(), length);
+        write_synced(append.join(name), &data)?;
+    }
+    let offsets = serde_json::to_vec(&offsets).map_err(|e| UoError(e.to_string()))?;
+    write_synced(staging.join(OFFSETS_FILE), &offsets)
+}
+
+/// Append the lines of the committed change in `commit`. Segments are cut back to their length
+/// before the change first, so running it again after an interruption appends nothing twice.
+pub fn append(folder: &Path, commit: &Path) -> Result<(), UoError> {
+    let offsets = commit.join(OFFSETS_FILE);
+    if !offsets.exists() {
+        return Ok(());
+    }
+    let offsets: BTreeMap<String, u64> =
+        serde_json::from_slice(&fs::read(offsets)?).map_err(|e| UoError(e.to_string()))?;
+    for (name, offset) in offsets {
+        let data = fs::read(commit.join(APPEND_FOLDER).join(&name))?;
+        let mut fd = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(folder.join(SEGMENT_FOLDER).join(name))?;
+        fd.set_len(offset)?;
+        fd.seek(SeekFrom::End(0))?;
+        fd.write_all(&data)?;
+        fd.sync_all()?;
+    }
+    sync_dir(&folder.join(SEGMENT_FOLDER))?;
+    Ok(())
+}
+
+/// Drop every line above `block_number`. Segments starting above it are deleted, the one
+/// containing it is rewritten through a temp file.
+pub fn remove_above(folder: &Path, block_number: u64) -> Result<(), UoError> {
+    let segment_folder = folder.join(SEGMENT_FOLDER);
+    for segment in segments(folder)? {
+        let path = segment_folder.join(&segment.file);
+        if segment.from_block > block_number {
+            fs::remove_file(path)?;
+        } else if segment.to_block > block_number {
+            let mut kept = Vec::new();
+            for line in fs::read(&path)?.split(|b| *b == b'\n') {
+                if block_of_line(line).is_some_and(|block| block <= block_number) {
+                    kept.extend_from_slice(line);
+                    kept.push(b'\n');
+                }
+            }
+            let tmp = path.with_extension(TMP_EXTENSION);
+            write_synced(tmp.clone(), &kept)?;
+            fs::rename(tmp, path)?;
+        }
+    }
+    sync_dir(&segment_folder)?;
+    Ok(())
+}
+
+/// Rewrite `index.json` from the segments on disk.
+pub fn write_index(folder: &Path) -> Result<(), UoError> {
+    let index =
+        serde_json::to_vec_pretty(&segments(folder)?).map_err(|e| UoError(e.to_string()))?;
+    let path = folder.join(SEGMENT_FOLDER).join(INDEX_FILE);
+    let tmp = path.with_extension(TMP_EXTENSION);
+    write_synced(tmp.clone(), &index)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Remove temp files left by a rewrite that was interrupted.
+pub fn remove_temp_files(folder: &Path) -> Result<(), UoError> {
+    for entry in fs::read_dir(folder.join(SEGMENT_FOLDER))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == TMP_EXTENSION) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// User operations stored in the segments overlapping blocks `from` to `to`. Fails once more
+/// than `max` are read.
+pub fn user_operations(
+    folder: &Path,
+    from: u64,
+    to: u64,
+    max: usize,
+) -> Result<Vec<UserOperationData>, UoError> {
+    let mut uos = Vec::new();
+    for segment in segments(folder)? {
+        if segment.kind != USER_OPERATIONS || segment.to_block < from || segment.from_block > to {
+            continue;
+        }
+        let data = fs::read(folder.join(SEGMENT_FOLDER).join(segment.file))?;
+        for line in data.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+            if uos.len() == max {
+                return Err(too_many_records(max));
+            }
+            uos.push(serde_json::from_slice(line).map_err(|e| UoError(e.to_string()))?);
+        }
+    }
+    Ok(uos)
+}
+
+/// Highest block number of any stored line.
+pub fn highest_block(folder: &Path) -> Result<u64, UoError> {
+    let mut highest = 0;
+    for segment in segments(folder)? {
+        let data = fs::read(folder.join(SEGMENT_FOLDER).join(segment.file))?;
+        for line in data.split(|b| *b == b'\n') {
+            highest = highest.max(block_of_line(line).unwrap_or(0));
+        }
+    }
+    Ok(highest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_segment_name, segment_name, Segment};
+
+    #[test]
+    fn segment_names() {
+        let name = segment_name("user_operations", 123_456, 100_000);
+        assert_eq!(name, "user_operations-000000100000-000000199999.jsonl");
+        assert_eq!(
+            parse_segment_name(&name),
+            Some(Segment {
+                file: name.clone(),
+                kind: "user_operations".to_string(),
+                from_block: 100_000,
+                to_block: 199_999,
+            })
+        );
+        assert_eq!(parse_segment_name("index.json"), None);
+    }
+}
@@ -0,0 +1,641 @@
+//! Fetching ranges of blocks from the rpc and committing them to the storage in block order.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ethers::{
+    contract::parse_log,
+    prelude::EthEvent,
+    providers::{Middleware, RpcError},
+    types::{Address, BlockNumber, Filter, Log, H256},
+};
+use futures::{stream, StreamExt, TryStreamExt};
+use thiserror::Error;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::{
+    bundle::find_bundled_operations,
+    cli::BlockTag,
+    constrant::EntryPointSpec,
+    database::Storage,
+    range::{is_range_limit_error, RangeSizer, RangeTooLarge},
+    reorg::{check_canonical, check_known, detect_reorg, BlockHashes, BlockReorged},
+    rpc::RpcProvider,
+    uo::{EntryPointEvent, EntryPointEventData, UserOperationData, UserOperationEvent},
+};
+
+async fn fetch_uo_logs(
+    start: u64,
+    end: u64,
+    provider: Arc<RpcProvider>,
+    chain_id: u64,
+    entry_points: &[EntryPointSpec],
+    parallelism: usize,
+) -> anyhow::Result<(Vec<UserOperationData>, Vec<EntryPointEventData>)> {
+    info!("Trying to get user operations from {} to {}", start, end);
+    let addresses: Vec<Address> = entry_points
+        .iter()
+        .filter(|ep| ep.deployed_block_number <= end)
+        .map(|ep| ep.address)
+        .collect();
+    let filter = Filter::new()
+        .from_block(start)
+        .to_block(end)
+        .address(addresses)
+        .topic0(EntryPointEvent::signatures());
+    let results = provider
+        .get_logs(&filter)
+        .await
+        .map_err(|e| match e.as_error_response() {
+            Some(response) if is_range_limit_error(&response.message) => {
+                anyhow::Error::new(RangeTooLarge {
+                    start,
+                    end,
+                    reason: response.message.clone(),
+                })
+            }
+            _ => e.into(),
+        })?;
+    let mut uo_logs = Vec::with_capacity(results.len());
+    let mut events = Vec::new();
+    for log in results {
+        let entry_point = entry_points
+            .iter()
+            .find(|ep| ep.address == log.address)
+            .expect("Logs are filtered by the EntryPoint addresses");
+        if log.topics[0] == UserOperationEvent::signature() {
+            uo_logs.push((log, entry_point));
+            continue;
+        }
+        let transaction_hash = log
+            .transaction_hash
+            .expect("Log belongs to transaction hash.");
+        let transaction_index = log.transaction_index.unwrap().as_u64();
+        let block_number = log.block_number.unwrap().as_u64();
+        let block_hash = log.block_hash.unwrap();
+        let log_index = log.log_index.unwrap().as_u64();
+        if let Some(event) = EntryPointEvent::decode(log)? {
+            events.push(EntryPointEventData {
+                chain_id,
+                entry_point: entry_point.address,
+                event,
+                transaction_hash,
+                transaction_index,
+                log_index,
+                block_number,
+                block_hash,
+            });
+        }
+    }
+
+    let data_result = fetch_user_operations(&provider, uo_logs, chain_id, parallelism).await?;
+    info!("Done getting data from {} to {}", start, end);
+    Ok((data_result, events))
+}
+
+/// A decoded `UserOperationEvent` log, waiting for its operation to be found in the bundle.
+struct UserOperationLog<'a> {
+    entry_point: &'a EntryPointSpec,
+    event: UserOperationEvent,
+    uo_hash: H256,
+    transaction_hash: H256,
+    transaction_index: u64,
+    log_index: u64,
+    block_number: u64,
+    block_hash: H256,
+}
+
+/// Build the `UserOperationData` of every `UserOperationEvent` log by finding the operations
+/// in the bundle transactions that emitted them.
+///
+/// Each bundle transaction is fetched and decoded once, however many user operations it holds.
+async fn fetch_user_operations(
+    provider: &RpcProvider,
+    uo_logs: Vec<(Log, &EntryPointSpec)>,
+    chain_id: u64,
+    parallelism: usize,
+) -> anyhow::Result<Vec<UserOperationData>> {
+    let mut parsed = Vec::with_capacity(uo_logs.len());
+    let mut bundles: HashMap<(H256, Address), Vec<H256>> = HashMap::new();
+    let mut transaction_hashes = Vec::new();
+    for (log, entry_point) in uo_logs {
+        let transaction_hash = log
+            .transaction_hash
+            .expect("Log belongs to transaction hash.");
+        let transaction_index = log.transaction_index.unwrap().as_u64();
+        let block_number = log.block_number.unwrap().as_u64();
+        let block_hash = log.block_hash.unwrap();
+        let log_index = log.log_index.unwrap().as_u64();
+        let event: UserOperationEvent = parse_log(log)?;
+        let uo_hash = H256::from(event.user_op_hash);
+        if !transaction_hashes.contains(&transaction_hash) {
+            transaction_hashes.push(transaction_hash);
+        }
+        bundles
+            .entry((transaction_hash, entry_point.address))
+            .or_default()
+            .push(uo_hash);
+        parsed.push(UserOperationLog {
+            entry_point,
+            event,
+            uo_hash,
+            transaction_hash,
+            transaction_index,
+            log_index,
+            block_number,
+            block_hash,
+        });
+    }
+
+    let transactions = provider
+        .as_ref()
+        .get_transactions(&transaction_hashes, parallelism)
+        .await?;
+    let mut bundled = HashMap::new();
+    let found = stream::iter(bundles)
+        .map(|((transaction_hash, address), uo_hashes)| {
+            let transaction = &transactions[&transaction_hash];
+            let entry_point = parsed
+                .iter()
+                .map(|log| log.entry_point)
+                .find(|ep| ep.address == address)
+                .expect("Bundles are grouped from parsed logs");
+            async move {
+                find_bundled_operations(provider, transaction, entry_point, chain_id, &uo_hashes)
+                    .await
+            }
+        })
+        .buffer_unordered(parallelism)
+        .try_collect::<Vec<_>>()
+        .await?;
+    found.into_iter().for_each(|ops| bundled.extend(ops));
+
+    let mut data_result = Vec::with_capacity(parsed.len());
+    for UserOperationLog {
+        entry_point,
+        event,
+        uo_hash,
+        transaction_hash,
+        transaction_index,
+        log_index,
+        block_number,
+        block_hash,
+    } in parsed
+    {
+        let result = bundled.remove(&uo_hash).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Could not find user operation {uo_hash:?} in transaction {transaction_hash:?}"
+            )
+        })?;
+        let data = UserOperationData {
+            chain_id,
+            entry_point: entry_point.address,
+            entry_point_version: entry_point.version,
+            factory: result.uo.factory(),
+            uo: result.uo,
+            uo_hash,
+            paymaster: event.paymaster,
+            success: event.success,
+            actual_gas_cost: event.actual_gas_cost,
+            actual_gas_price: event.actual_gas_price,
+            transaction_hash,
+            transaction_index,
+            log_index,
+            block_number,
+            block_hash,
+            aggregator: result.aggregator,
+            aggregated_signature: result.aggregated_signature,
+        };
+        println!("Find {data:?}");
+        data_result.push(data);
+    }
+    Ok(data_result)
+}
+
+/// The provider doesn't have a block of the range yet, most likely because it is behind the
+/// endpoint the head block came from. The range is fetched again on the next poll.
+#[derive(Error, Debug)]
+#[error("block {0} is not available yet")]
+pub struct BlockNotAvailable(pub u64);
+
+/// One fetched range, waiting to be committed.
+struct FetchedRange {
+    end: u64,
+    /// Hash of the start block according to the parent hash of the block after it.
+    parent_hash: H256,
+    end_hash: H256,
+    uos: Vec<UserOperationData>,
+    events: Vec<EntryPointEventData>,
+    elapsed: Duration,
+}
+
+/// Fetch the user operations and events from `start` to `end`, with up to `parallelism`
+/// requests in flight for the transactions of the range.
+///
+/// Fails with [`BlockReorged`] when a log doesn't belong to the canonical chain anymore. The
+/// returned `elapsed` only covers fetching the logs and transactions, not the header reads.
+async fn fetch_range(
+    start: u64,
+    end: u64,
+    provider: Arc<RpcProvider>,
+    chain_id: u64,
+    entry_points: &[EntryPointSpec],
+    parallelism: usize,
+) -> anyhow::Result<FetchedRange> {
+    // Take the boundary hashes before fetching logs so a reorg in between is caught below.
+    let parent_hash = provider
+        .get_block(start + 1)
+        .await?
+        .map(|block| block.parent_hash)
+        .ok_or(BlockNotAvailable(start + 1))?;
+    let end_hash = provider
+        .get_block(end)
+        .await?
+        .and_then(|block| block.hash)
+        .ok_or(BlockNotAvailable(end))?;
+    let started = Instant::now();
+    let (uos, events) = fetch_uo_logs(
+        start,
+        end,
+        provider.clone(),
+        chain_id,
+        entry_points,
+        parallelism,
+    )
+    .await?;
+    let elapsed = started.elapsed();
+    let logs = uos.iter().map(|uo| (uo.block_number, uo.block_hash)).chain(
+        events
+            .iter()
+            .map(|event| (event.block_number, event.block_hash)),
+    );
+    // While the end block keeps its hash, every block below it does too, so the logs only need
+    // to agree with the boundaries. Otherwise each block holding logs is checked on its own.
+    let recheck = provider
+        .get_block(end)
+        .await?
+        .and_then(|block| block.hash)
+        .ok_or(BlockNotAvailable(end))?;
+    let end_hash = if recheck == end_hash {
+        let known = BTreeMap::from([(start, parent_hash), (end, end_hash)]);
+        check_known(logs, &known)?;
+        end_hash
+    } else {
+        warn!("Block {end} changed while fetching its range, checking every block with logs");
+        let known = BTreeMap::from([(end, recheck)]);
+        check_canonical(provider.as_ref(), logs, &known, parallelism).await?;
+        recheck
+    };
+    Ok(FetchedRange {
+        end,
+        parent_hash,
+        end_hash,
+        uos,
+        events,
+        elapsed,
+    })
+}
+
+/// Get the highest block the indexer is allowed to index, following `tag` and staying
+/// `confirmations` blocks behind it.
+pub async fn get_head_block(
+    provider: &RpcProvider,
+    tag: BlockTag,
+    confirmations: u64,
+) -> anyhow::Result<u64> {
+    let head = match tag {
+        BlockTag::Latest => provider.get_block_number().await?.as_u64(),
+        tag => provider
+            .get_block(BlockNumber::from(tag))
+            .await?
+            .and_then(|block| block.number)
+            .ok_or_else(|| anyhow::anyhow!("The rpc does not support the {tag:?} block tag"))?
+            .as_u64(),
+    };
+    Ok(head.saturating_sub(confirmations))
+}
+
+/// Everything the main loop carries from one range to the next.
+pub struct Indexer {
+    pub provider: Arc<RpcProvider>,
+    pub chain_id: u64,
+    pub entry_points: Vec<EntryPointSpec>,
+    pub db: Storage,
+    pub hashes: BlockHashes,
+    pub sizer: RangeSizer,
+    pub parallelism: usize,
+    pub shutdown: watch::Receiver<bool>,
+}
+
+impl Indexer {
+    pub fn stopping(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Index from `start` up to `latest` and return the block the indexer should continue from.
+    ///
+    /// Up to `parallelism` ranges are fetched concurrently but committed strictly in block
+    /// order, so the checkpoint never moves past a range that hasn't been written. The ranges
+    /// share the `parallelism` budget for fetching their transactions.
+    ///
+    /// If the chain reorganized below `start`, every user operation and event above the fork
+    /// point is rolled back instead and the fork point is returned so the range gets indexed
+    /// again. If the provider refuses a range as too large, the range size is halved and the
+    /// start of that range is returned. If a range doesn't continue the chain the previous one
+    /// was fetched from, or holds logs of blocks that were reorged out while fetching, the rest
+    /// of the batch is dropped and the last committed block is returned so reorg detection runs
+    /// again.
+    ///
+    /// Once a shutdown is requested, ranges that aren't committed yet are dropped and the last
+    /// committed block is returned. If the provider misses a block of the batch, the last
+    /// committed block is returned, or [`BlockNotAvailable`] when no range was committed.
+    pub async fn index_ranges(&mut self, start: u64, latest: u64) -> anyhow::Result<u64> {
+        if self.stopping() {
+            return Ok(start);
+        }
+        if let Some(fork_point) = detect_reorg(self.provider.as_ref(), &self.hashes, start).await? {
+            warn!("Chain reorganization detected, rolling back to block {fork_point}");
+            let hash = self.hashes.get(fork_point);
+            self.db.rollback(fork_point, hash).await?;
+            self.hashes.remove_above(fork_point);
+            return Ok(fork_point);
+        }
+
+        let step = self.sizer.step();
+        let mut ranges = Vec::with_capacity(self.parallelism);
+        let mut range_start = start;
+        while range_start < latest && ranges.len() < self.parallelism {
+            let range_end = latest.min(range_start + step);
+            ranges.push((range_start, range_end));
+            range_start = range_end;
+        }
+
+        let provider = self.provider.clone();
+        let entry_points = self.entry_points.clone();
+        let chain_id = self.chain_id;
+        let fetch_parallelism = (self.parallelism / ranges.len().max(1)).max(1);
+        let mut fetched = stream::iter(ranges)
+            .map(|(range_start, range_end)| {
+                fetch_range(
+                    range_start,
+                    range_end,
+                    provider.clone(),
+                    chain_id,
+                    &entry_points,
+                    fetch_parallelism,
+                )
+            })
+            .buffered(self.parallelism);
+
+        let mut current_block = start;
+        loop {
+            // Only fetching is abandoned on shutdown, a range being committed always finishes.
+            let range = tokio::select! {
+                biased;
+                Ok(()) = self.shutdown.changed() => None,
+                range = fetched.next() => range,
+            };
+            if self.stopping() {
+                info!("Abandoning the ranges above block {current_block} to shut down");
+                return Ok(current_block);
+            }
+            let Some(range) = range else {
+                break;
+            };
+            let range = match range {
+                Ok(range) => range,
+                Err(e) => {
+                    // The next call fails on the same block right away and returns the error.
+                    if e.is::<BlockNotAvailable>() && current_block > start {
+                        warn!("{e}, stopping at block {current_block}");
+                        return Ok(current_block);
+                    }
+                    if let Some(reorged) = e.downcast_ref::<BlockReorged>() {
+                        warn!("{reorged}, fetching the blocks above {current_block} again");
+                        return Ok(current_block);
+                    }
+                    match e.downcast_ref::<RangeTooLarge>() {
+                        Some(too_large) if self.sizer.on_limit_error() => {
+                            warn!(
+                                "{too_large}, shrinking the range to {} blocks",
+                                self.sizer.step()
+                            );
+                            return Ok(current_block);
+                        }
+                        _ => return Err(e),
+                    }
+                }
+            };
+            let expected = match self.hashes.get(current_block) {
+                Some(hash) => hash,
+                None => range.parent_hash,
+            };
+            if range.parent_hash != expected {
+                warn!(
+                    "Range ending at block {} doesn't continue block {current_block}, expected parent {expected:?} but got {:?}",
+                    range.end, range.parent_hash
+                );
+                return Ok(current_block);
+            }
+            self.sizer
+                .on_success(range.uos.len() + range.events.len(), range.elapsed);
+            self.db
+                .commit(range.uos, range.events, range.end, range.end_hash)
+                .await?;
+            self.hashes.insert(range.end, range.end_hash);
+            current_block = range.end;
+        }
+        Ok(current_block)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use ethers::{
+        prelude::EthEvent,
+        providers::Provider,
+        types::{Address, H256},
+    };
+    use serde_json::{json, Value};
+    use tokio::sync::watch;
+
+    use super::{BlockNotAvailable, Indexer};
+    use crate::{
+        constrant::EntryPointSpec,
+        database::{FileDB, Layout, Storage},
+        range::RangeSizer,
+        reorg::{BlockHashes, REORG_WINDOW},
+        rpc::{RetryPolicy, RpcClient},
+        test_utils::{block_hash as hash, mock_rpc_delayed, temp_dir},
+        uo::{BeforeExecutionEvent, EntryPointVersion},
+    };
+
+    fn number(value: &Value) -> u64 {
+        u64::from_str_radix(value.as_str().unwrap().trim_start_matches("0x"), 16).unwrap()
+    }
+
+    fn block(n: u64, parent_hash: H256) -> Value {
+        json!({"number": format!("{n:#x}"), "hash": hash(n), "parentHash": parent_hash})
+    }
+
+    /// An indexer over a chain up to block `head` whose block `n` has hash `hash(n)`, fetching
+    /// ranges of 10 blocks.
+    ///
+    /// `parent` gives the parent hash of a block and `logs` the logs of a `eth_getLogs` call
+    /// starting at a block. `eth_getLogs` calls are answered after `delay`.
+    async fn indexer(
+        name: &str,
+        head: u64,
+        parent: impl Fn(u64) -> H256 + Send + Sync + 'static,
+        logs: impl Fn(u64) -> Value + Send + Sync + 'static,
+        delay: impl Fn(u64) -> Duration + Send + Sync + 'static,
+    ) -> (Indexer, watch::Sender<bool>) {
+        let url = mock_rpc_delayed(
+            move |method, params| match method {
+                "eth_getBlockByNumber" => match number(&params[0]) {
+                    n if n > head => Ok(Value::Null),
+                    n => Ok(block(n, parent(n))),
+                },
+                "eth_getLogs" => Ok(logs(number(&params[0]["fromBlock"]))),
+                method => Err(format!("unexpected method {method}")),
+            },
+            move |method, params| match method {
+                "eth_getLogs" => delay(number(&params[0]["fromBlock"])),
+                _ => Duration::ZERO,
+            },
+        )
+        .await;
+        let retry = RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        };
+        let client = RpcClient::new(
+            vec![url.parse().unwrap()],
+            Duration::from_secs(5),
+            1,
+            retry,
+            None,
+        );
+        let db = FileDB::new(temp_dir(name), Layout::Files).unwrap();
+        let (stop, shutdown) = watch::channel(false);
+        let indexer = Indexer {
+            provider: Arc::new(Provider::new(client)),
+            chain_id: 1,
+            entry_points: vec![EntryPointSpec {
+                address: Address::from_low_u64_be(1),
+                version: EntryPointVersion::V0_6,
+                deployed_block_number: 0,
+            }],
+            db: Storage::new(Box::new(db)).await,
+            hashes: BlockHashes::new(REORG_WINDOW),
+            sizer: RangeSizer::new(10, 10),
+            parallelism: 4,
+            shutdown,
+        };
+        (indexer, stop)
+    }
+
+    fn parent(n: u64) -> H256 {
+        hash(n.saturating_sub(1))
+    }
+
+    #[tokio::test]
+    async fn commits_ranges_in_order() {
+        // The first range finishes last.
+        let delay = |from| match from {
+            0 => Duration::from_millis(300),
+            _ => Duration::ZERO,
+        };
+        let (mut indexer, _stop) = indexer("in-order", 40, parent, |_| json!([]), delay).await;
+
+        assert_eq!(indexer.index_ranges(0, 40).await.unwrap(), 40);
+        assert_eq!(indexer.db.get_last_block().await.unwrap(), 40);
+        assert_eq!(indexer.hashes.get(40), Some(hash(40)));
+    }
+
+    #[tokio::test]
+    async fn drops_batch_after_broken_chain() {
+        // Block 21 doesn't build on the block 20 the previous range ended with.
+        let broken = |n| match n {
+            21 => H256::repeat_byte(0xff),
+            n => parent(n),
+        };
+        let (mut indexer, _stop) = indexer(
+            "broken-chain",
+            40,
+            broken,
+            |_| json!([]),
+            |_| Duration::ZERO,
+        )
+        .await;
+
+        assert_eq!(indexer.index_ranges(0, 40).await.unwrap(), 20);
+        assert_eq!(indexer.db.get_last_block().await.unwrap(), 20);
+        assert_eq!(indexer.hashes.get(30), None);
+    }
+
+    #[tokio::test]
+    async fn drops_batch_after_reorged_log() {
+        // The range from block 10 returns a log of a block 20 that isn't canonical.
+        let logs = |from| match from {
+            10 => json!([{
+                "address": Address::from_low_u64_be(1),
+                "topics": [BeforeExecutionEvent::signature()],
+                "data": "0x",
+                "blockHash": H256::repeat_byte(0xee),
+                "blockNumber": "0x14",
+                "transactionHash": H256::from_low_u64_be(0x2014),
+                "transactionIndex": "0x0",
+                "logIndex": "0x0",
+            }]),
+            _ => json!([]),
+        };
+        let (mut indexer, _stop) =
+            indexer("reorged-log", 40, parent, logs, |_| Duration::ZERO).await;
+
+        assert_eq!(indexer.index_ranges(0, 40).await.unwrap(), 10);
+        assert_eq!(indexer.db.get_last_block().await.unwrap(), 10);
+    }
+
+    #[tokio::test]
+    async fn drops_uncommitted_ranges_on_shutdown() {
+        let delay = |from| match from {
+            0 => Duration::ZERO,
+            _ => Duration::from_secs(3),
+        };
+        let (mut indexer, stop) = indexer("shutdown", 40, parent, |_| json!([]), delay).await;
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            stop.send(true).unwrap();
+        });
+
+        assert_eq!(indexer.index_ranges(0, 40).await.unwrap(), 10);
+        assert_eq!(indexer.db.get_last_block().await.unwrap(), 10);
+        assert!(indexer.stopping());
+    }
+
+    #[tokio::test]
+    async fn waits_for_missing_block() {
+        // The provider is behind and doesn't have block 31 yet.
+        let (mut indexer, _stop) = indexer(
+            "missing-block",
+            30,
+            parent,
+            |_| json!([]),
+            |_| Duration::ZERO,
+        )
+        .await;
+
+        assert_eq!(indexer.index_ranges(0, 40).await.unwrap(), 30);
+        let error = indexer.index_ranges(30, 40).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<BlockNotAvailable>().unwrap().0, 31);
+        assert_eq!(indexer.db.get_last_block().await.unwrap(), 30);
+    }
+}
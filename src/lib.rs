@@ -0,0 +1,17 @@
+//! Indexer of ERC-4337 user operations and EntryPoint events. The `uoindexer` binary fills a
+//! storage backend, and `database::Storage` reads the indexed records back.
+
+pub mod bundle;
+pub mod cli;
+pub mod constrant;
+pub mod database;
+pub mod indexer;
+pub mod range;
+pub mod reorg;
+pub mod rpc;
+pub mod shutdown;
+pub mod subscription;
+#[cfg(test)]
+mod test_utils;
+pub mod trace;
+pub mod uo;
@@ -1,80 +1,26 @@
-mod cli;
-mod constrant;
-mod database;
-mod uo;
-
 use std::{path::PathBuf, str::FromStr, sync::Arc, time::Duration};
 
 use clap::Parser;
-use cli::Cli;
-use ethers::{
-    prelude::EthEvent,
-    providers::{Http, Middleware, Provider},
-    types::{Address, Filter},
-};
+use ethers::providers::{Middleware, Provider};
 
 use tokio::time;
-use tokio_retry::{strategy::FixedInterval, Retry};
-use tracing::{debug, info};
-
-use crate::{
-    cli::Mode,
-    constrant::{ENTRY_POINT_ADDR, SUPPORT_CHAIN},
-    database::{mongodb::MongoDB, rocksdb_storage::RocksDb, DataBase, FileDB, Storage},
-    uo::{HandleOpsCall, UserOperationData, UserOperationEvent},
+use tracing::{debug, info, warn};
+
+use uoindexer::{
+    cli::{Cli, FileLayout, Mode},
+    constrant::SUPPORT_CHAIN,
+    database::{
+        mongodb::MongoDB, postgres::Postgres, rocksdb_storage::RocksDb, FileDB, Layout, Storage,
+        StoreMetadata,
+    },
+    indexer::{get_head_block, BlockNotAvailable, Indexer},
+    range::RangeSizer,
+    reorg::{BlockHashes, REORG_WINDOW},
+    rpc::{RateLimiter, RetryPolicy, RpcClient},
+    shutdown::spawn_shutdown_listener,
+    subscription::spawn_subscription,
 };
 
-const RETRY_INTERVAL_MILLI: u64 = 5000;
-const MAX_STEP: u64 = 10;
-const INTERBAL: u64 = 13;
-
-async fn fetch_uo_logs(
-    start: u64,
-    end: u64,
-    provider: Arc<Provider<Http>>,
-    chain_id: u64,
-) -> anyhow::Result<Vec<UserOperationData>> {
-    info!("Trying to get user operations from {} to {}", start, end);
-    let filter = Filter::new()
-        .from_block(start)
-        .to_block(end)
-        .address(Address::from_str(ENTRY_POINT_ADDR).expect("Const is formal address"))
-        .topic0(UserOperationEvent::signature());
-    let p = provider.clone();
-    let results = p.get_logs(&filter).await?;
-    let mut data_result = Vec::with_capacity(results.len());
-    for log in results {
-        let transaction_hash = log
-            .transaction_hash
-            .expect("Log belongs to transaction hash.");
-        let res = p.get_transaction(transaction_hash).await?;
-        let transaction = res.expect("Transaction should exist");
-        let handles = <HandleOpsCall as ethers::core::abi::AbiDecode>::decode(transaction.input)?;
-        let result = handles
-            .ops
-            .iter()
-            .find(|&op| {
-                log.topics[1]
-                    == op.uo_hash(Address::from_str(ENTRY_POINT_ADDR).expect("Good"), chain_id)
-            })
-            .unwrap()
-            .to_owned();
-        let uo_hash = result.hash();
-        let data = UserOperationData {
-            uo: result,
-            uo_hash,
-            transaction_hash,
-            transaction_index: log.transaction_index.unwrap().as_u64(),
-            block_number: log.block_number.unwrap().as_u64(),
-            block_hash: log.block_hash.unwrap(),
-        };
-        println!("Find {data:?}");
-        data_result.push(data);
-    }
-    info!("Done getting data from {} to {}", start, end);
-    Ok(data_result)
-}
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
@@ -83,19 +29,56 @@ async fn main() -> anyhow::Result<()> {
 
     let db: Storage = match config.mode {
         Mode::File(args) => {
-            Storage::new(Box::new(FileDB::new(PathBuf::from_str(&args.db_path)?)?)).await
+            let layout = match args.layout {
+                FileLayout::Files => Layout::Files,
+                FileLayout::Segments => Layout::Segments {
+                    blocks: args.segment_blocks.max(1),
+                },
+            };
+            Storage::new(Box::new(FileDB::new(
+                PathBuf::from_str(&args.db_path)?,
+                layout,
+            )?))
+            .await
+        }
+        Mode::MongoDB(args) => {
+            Storage::new(Box::new(MongoDB::new(args.uri, args.namespace).await?)).await
         }
-        Mode::MongoDB(args) => Storage::new(Box::new(MongoDB::new(args.uri).await?)).await,
         Mode::RocksDB(args) => {
             Storage::new(Box::new(RocksDb::new(PathBuf::from_str(&args.db_path)?)?)).await
         }
+        Mode::Postgres(args) => {
+            Storage::new(Box::new(Postgres::new(&args.url, &args.schema).await?)).await
+        }
     };
-    let retry = FixedInterval::from_millis(RETRY_INTERVAL_MILLI);
 
     let last_block = db.get_last_block().await?;
-    let provider = Arc::new(Provider::<Http>::try_from(config.rpc_url.clone())?);
-    let mut latest_block = provider.clone().get_block_number().await?.as_u64();
-    info!("Latest block in the network is {:?}", latest_block);
+    if config.quorum > config.rpc_urls.len() {
+        return Err(anyhow::anyhow!(
+            "The quorum {} is larger than the {} rpc urls given.",
+            config.quorum,
+            config.rpc_urls.len()
+        ));
+    }
+    let provider = Arc::new(Provider::new(RpcClient::new(
+        config.rpc_urls.clone(),
+        Duration::from_secs(config.rpc_timeout),
+        config.quorum,
+        RetryPolicy {
+            max_attempts: config.rpc_max_attempts.max(1),
+            initial_backoff: Duration::from_millis(config.rpc_backoff),
+            max_backoff: Duration::from_millis(config.rpc_max_backoff),
+        },
+        config
+            .rpc_rate_limit
+            .map(|rate| RateLimiter::new(rate, config.rpc_method_costs.clone())),
+    )));
+    let mut latest_block =
+        get_head_block(&provider, config.block_tag, config.confirmations).await?;
+    info!(
+        "Latest {:?} block in the network is {:?} with {} confirmations",
+        config.block_tag, latest_block, config.confirmations
+    );
 
     let chain_id = provider.clone().get_chainid().await?.as_u64();
     if chain_id != config.chain_id {
@@ -104,56 +87,119 @@ async fn main() -> anyhow::Result<()> {
         ));
     }
 
-    let chain_spec = match SUPPORT_CHAIN.iter().find(|&c| c.chain_id == chain_id) {
-        Some(spec) => spec.clone(),
+    let chain_spec = SUPPORT_CHAIN.iter().find(|&c| c.chain_id == chain_id);
+    let entry_points = match chain_spec {
+        _ if !config.entry_points.is_empty() => config.entry_points.clone(),
+        Some(spec) => spec.entry_points.clone(),
         None => {
             return Err(anyhow::anyhow!(
-                "The chain id {chain_id} is not supported right now. "
+                "The chain id {chain_id} is not supported right now. Pass --entry-point to index it anyway."
             ))
         }
     };
+    for ep in entry_points.iter() {
+        info!(
+            "Indexing EntryPoint {:?} {:?} deployed at block {}",
+            ep.address, ep.version, ep.deployed_block_number
+        );
+    }
+    db.check_metadata(
+        &StoreMetadata::new(chain_id, entry_points.iter().map(|ep| ep.address).collect()),
+        config.adopt_store,
+    )
+    .await?;
 
     let mut current_block = {
         if last_block == 0 {
             info!("There is no history user operation in current database now. The indexer would start from scratch and it would take some time to finish.");
-            chain_spec.contract_deployed_block_number
+            entry_points
+                .iter()
+                .map(|ep| ep.deployed_block_number)
+                .min()
+                .unwrap_or_default()
         } else {
             last_block
         }
     };
-    info!("Current block is {current_block:?}");
-
-    let mut interval = time::interval(Duration::from_secs(INTERBAL)); // 13 is basically the interval between each block in eth
+    info!(
+        "Current block of {} is {current_block:?}",
+        chain_spec.map_or("custom chain", |spec| spec.name.as_str())
+    );
+
+    let mut shutdown = spawn_shutdown_listener();
+    let mut interval = time::interval(Duration::from_secs(config.poll_interval));
+    let mut subscription = config.ws_url.clone().map(|url| {
+        spawn_subscription(
+            url,
+            config.ws_subscription,
+            entry_points.iter().map(|ep| ep.address).collect(),
+        )
+    });
+    // The stored hash of the last block lets a restart notice a reorg that happened while the
+    // indexer was down.
+    let mut hashes = BlockHashes::new(REORG_WINDOW);
+    if let Some(hash) = db.get_last_block_hash().await? {
+        hashes.insert(last_block, hash);
+    }
+    let mut indexer = Indexer {
+        provider: provider.clone(),
+        chain_id,
+        entry_points,
+        db,
+        hashes,
+        sizer: RangeSizer::new(config.min_step, config.max_step),
+        parallelism: config.parallelism.max(1),
+        shutdown: shutdown.clone(),
+    };
 
     loop {
         if current_block < latest_block {
-            if current_block + MAX_STEP > latest_block {
-                let res =
-                    fetch_uo_logs(current_block, latest_block, provider.clone(), chain_id).await?;
-
-                db.write_user_operation(res).await?;
-                current_block = latest_block;
-                db.write_last_block(current_block).await?;
-            } else {
-                info!("Indexer is going to continuously fetching logs from {current_block} to {latest_block}");
-                // When the current indexer is not up to date and fall behind more than MAX_STEP blocks
-                for target in (current_block + MAX_STEP..latest_block).step_by(MAX_STEP as usize) {
-                    let res =
-                        fetch_uo_logs(current_block, target, provider.clone(), chain_id).await?;
-                    db.write_user_operation(res).await?;
-                    current_block = target;
-                    db.write_last_block(current_block).await?;
+            info!("Indexer is going to continuously fetching logs from {current_block} to {latest_block}");
+        }
+        while current_block < latest_block && !indexer.stopping() {
+            match indexer.index_ranges(current_block, latest_block).await {
+                Ok(block) => current_block = block,
+                Err(e) if e.is::<BlockNotAvailable>() => {
+                    warn!("{e}, trying again on the next poll");
+                    break;
                 }
-            };
+                Err(e) => return Err(e),
+            }
+            if let Some((used, per_second)) = (*provider).as_ref().usage() {
+                info!(
+                    "Used {used} rpc compute units so far, {per_second:.1} per second on average"
+                );
+            }
         }
 
-        interval.tick().await;
-        latest_block = Retry::spawn(retry.clone(), || {
-            info!("Trying to get the latest block.");
-            provider.get_block_number()
-        })
-        .await?
-        .as_u64();
+        if indexer.stopping() {
+            break;
+        }
+
+        match subscription.as_mut() {
+            Some(heads) => {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    Ok(()) = heads.changed() => debug!("New block {} from the subscription", *heads.borrow()),
+                    Ok(()) = shutdown.changed() => break,
+                }
+            }
+            None => {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    Ok(()) = shutdown.changed() => break,
+                }
+            }
+        }
+        info!("Trying to get the latest block.");
+        match get_head_block(&provider, config.block_tag, config.confirmations).await {
+            Ok(block) => latest_block = block,
+            Err(e) => warn!("Could not get the latest block, trying again on the next poll: {e}"),
+        }
         info!("Latest block is {latest_block:?}")
     }
+
+    indexer.db.flush().await?;
+    info!("Indexer stopped cleanly, the next run continues from block {current_block}");
+    Ok(())
 }
@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use thiserror::Error;
+
+/// Ranges returning fewer logs than this, faster than `GROW_DURATION`, make the next range larger.
+const GROW_MAX_LOGS: usize = 1000;
+const GROW_DURATION: Duration = Duration::from_secs(3);
+/// Ranges slower than this make the next range smaller even without a provider error.
+const SHRINK_DURATION: Duration = Duration::from_secs(30);
+
+/// Lowercase fragments of the JSON-RPC error messages providers send back when an
+/// `eth_getLogs` range is too large. Only phrases about the range or the size of the response,
+/// a code like -32005 is shared with rate limiting.
+const LIMIT_ERRORS: [&str; 9] = [
+    "query returned more than",
+    "response size exceeded",
+    "response size is larger",
+    "too many results",
+    "too many logs",
+    "range too large",
+    "range is too large",
+    "maximum block range",
+    "eth_getlogs is limited to",
+];
+
+#[derive(Error, Debug)]
+#[error("block range {start} to {end} is too large for the provider: {reason}")]
+pub struct RangeTooLarge {
+    pub start: u64,
+    pub end: u64,
+    pub reason: String,
+}
+
+/// Whether the message of a JSON-RPC error says the requested range was too large.
+pub fn is_range_limit_error(message: &str) -> bool {
+    let message = message.to_lowercase();
+    LIMIT_ERRORS.iter().any(|pattern| message.contains(pattern))
+}
+
+/// Picks how many blocks go into one `eth_getLogs` request. The range doubles while responses
+/// are small and fast and halves when they are slow or the provider refuses them.
+#[derive(Debug)]
+pub struct RangeSizer {
+    step: u64,
+    min: u64,
+    max: u64,
+}
+
+impl RangeSizer {
+    pub fn new(min: u64, max: u64) -> Self {
+        let min = min.max(1);
+        Self {
+            step: min,
+            min,
+            max: max.max(min),
+        }
+    }
+
+    pub fn step(&self) -> u64 {
+        self.step
+    }
+
+    pub fn on_success(&mut self, logs: usize, elapsed: Duration) {
+        if logs < GROW_MAX_LOGS && elapsed < GROW_DURATION {
+            self.step = (self.step * 2).min(self.max);
+        } else if elapsed > SHRINK_DURATION {
+            self.step = (self.step / 2).max(self.min);
+        }
+    }
+
+    /// Halve the range after the provider refused it. Returns `false` when the range is already
+    /// at the minimum and can't shrink anymore.
+    pub fn on_limit_error(&mut self) -> bool {
+        if self.step == self.min {
+            return false;
+        }
+        self.step = (self.step / 2).max(self.min);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{is_range_limit_error, RangeSizer};
+
+    #[test]
+    fn range_sizer_grows_and_shrinks() {
+        let mut sizer = RangeSizer::new(10, 50);
+        assert_eq!(sizer.step(), 10);
+        sizer.on_success(0, Duration::from_millis(100));
+        assert_eq!(sizer.step(), 20);
+        sizer.on_success(0, Duration::from_millis(100));
+        sizer.on_success(0, Duration::from_millis(100));
+        assert_eq!(sizer.step(), 50);
+        sizer.on_success(5000, Duration::from_millis(100));
+        assert_eq!(sizer.step(), 50);
+        sizer.on_success(10, Duration::from_secs(60));
+        assert_eq!(sizer.step(), 25);
+
+        assert!(sizer.on_limit_error());
+        assert_eq!(sizer.step(), 12);
+        assert!(sizer.on_limit_error());
+        assert_eq!(sizer.step(), 10);
+        assert!(!sizer.on_limit_error());
+    }
+
+    #[test]
+    fn range_limit_errors() {
+        assert!(is_range_limit_error(
+            "query returned more than 10000 results"
+        ));
+        assert!(is_range_limit_error(
+            "Log response size exceeded. You can make eth_getLogs requests with up to a 2K block range"
+        ));
+        assert!(is_range_limit_error(
+            "eth_getLogs is limited to a 10,000 range"
+        ));
+        assert!(!is_range_limit_error("connection refused"));
+        assert!(!is_range_limit_error(
+            "daily request count limited to 100000"
+        ));
+        assert!(!is_range_limit_error("invalid block range params"));
+    }
+}
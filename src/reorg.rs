@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+
+use ethers::{providers::Middleware, types::H256};
+use futures::{stream, StreamExt, TryStreamExt};
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// How many indexed range boundaries we remember for reorg detection.
+pub const REORG_WINDOW: usize = 256;
+/// How far below the oldest known block we roll back when none of the known blocks is canonical
+/// anymore, like after a restart that only knows the last block. Mainnet blocks are final after
+/// two epochs of 32 blocks.
+pub const MAX_REORG_DEPTH: u64 = 64;
+
+/// Data fetched for a block no longer matches the canonical chain, the range has to be fetched
+/// again after reorg detection ran.
+#[derive(Error, Debug)]
+#[error(
+    "block {block_number} changed while fetching, expected hash {expected:?} but got {found:?}"
+)]
+pub struct BlockReorged {
+    pub block_number: u64,
+    pub expected: H256,
+    pub found: H256,
+}
+
+/// Hashes of recently indexed blocks, keyed by block number.
+pub struct BlockHashes {
+    window: usize,
+    hashes: BTreeMap<u64, H256>,
+}
+
+impl BlockHashes {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            hashes: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, block_number: u64, hash: H256) {
+        self.hashes.insert(block_number, hash);
+        while self.hashes.len() > self.window {
+            self.hashes.pop_first();
+        }
+    }
+
+    pub fn get(&self, block_number: u64) -> Option<H256> {
+        self.hashes.get(&block_number).copied()
+    }
+
+    /// Forget every block above `block_number`.
+    pub fn remove_above(&mut self, block_number: u64) {
+        self.hashes.split_off(&(block_number + 1));
+    }
+
+    pub fn lowest(&self) -> Option<u64> {
+        self.hashes.keys().next().copied()
+    }
+
+    /// Known blocks from the newest to the oldest one.
+    pub fn iter_desc(&self) -> impl Iterator<Item = (u64, H256)> + '_ {
+        self.hashes.iter().rev().map(|(n, h)| (*n, *h))
+    }
+}
+
+/// Check whether the block we indexed last is still canonical by comparing it with the parent
+/// hash of the next block. Returns the fork point if a reorg happened.
+pub async fn detect_reorg<M: Middleware>(
+    provider: &M,
+    hashes: &BlockHashes,
+    current_block: u64,
+) -> anyhow::Result<Option<u64>>
+where
+    M::Error: 'static,
+{
+    let known = match hashes.get(current_block) {
+        Some(hash) => hash,
+        None => return Ok(None),
+    };
+    let next = match provider.get_block(current_block + 1).await? {
+        Some(block) => block,
+        None => return Ok(None),
+    };
+    if next.parent_hash == known {
+        return Ok(None);
+    }
+    warn!(
+        "Block {current_block} was reorged out, expected hash {known:?} but the next block points to {:?}",
+        next.parent_hash
+    );
+    find_fork_point(provider, hashes).await.map(Some)
+}
+
+/// Check that the fetched logs agree with each other and with the `known` hashes, without
+/// asking the provider.
+///
+/// Returns the hash the logs report for every block that isn't in `known`.
+pub fn check_known(
+    logs: impl IntoIterator<Item = (u64, H256)>,
+    known: &BTreeMap<u64, H256>,
+) -> Result<BTreeMap<u64, H256>, BlockReorged> {
+    let mut blocks = BTreeMap::new();
+    for (block_number, found) in logs {
+        let expected = match known.get(&block_number) {
+            Some(expected) => *expected,
+            None => *blocks.entry(block_number).or_insert(found),
+        };
+        if expected != found {
+            return Err(BlockReorged {
+                block_number,
+                expected,
+                found,
+            });
+        }
+    }
+    Ok(blocks)
+}
+
+/// Check that the block hash of every fetched log is the canonical hash of its block.
+///
+/// `known` holds hashes already taken from the chain for some of the blocks, the other blocks are
+/// fetched with up to `parallelism` requests in flight.
+pub async fn check_canonical<M: Middleware>(
+    provider: &M,
+    logs: impl IntoIterator<Item = (u64, H256)>,
+    known: &BTreeMap<u64, H256>,
+    parallelism: usize,
+) -> anyhow::Result<()>
+where
+    M::Error: 'static,
+{
+    let blocks = check_known(logs, known)?;
+    let canonical: Vec<(u64, Option<H256>)> = stream::iter(blocks.keys().copied())
+        .map(|block_number| async move {
+            let hash = provider
+                .get_block(block_number)
+                .await?
+                .and_then(|block| block.hash);
+            anyhow::Ok((block_number, hash))
+        })
+        .buffer_unordered(parallelism.max(1))
+        .try_collect()
+        .await?;
+    for (block_number, hash) in canonical {
+        let found = blocks[&block_number];
+        let expected = hash.unwrap_or_default();
+        if expected != found {
+            return Err(BlockReorged {
+                block_number,
+                expected,
+                found,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Walk back through the known hashes until one of them matches the canonical chain again.
+async fn find_fork_point<M: Middleware>(provider: &M, hashes: &BlockHashes) -> anyhow::Result<u64>
+where
+    M::Error: 'static,
+{
+    for (number, hash) in hashes.iter_desc() {
+        let block = provider.get_block(number).await?;
+        if block.and_then(|b| b.hash) == Some(hash) {
+            info!("Found fork point at block {number}");
+            return Ok(number);
+        }
+    }
+    let fallback = hashes.lowest().unwrap_or(0).saturating_sub(MAX_REORG_DEPTH);
+    warn!("Reorg is deeper than the tracked window, falling back to block {fallback}");
+    Ok(fallback)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use ethers::{
+        providers::{Http, Provider},
+        types::H256,
+    };
+    use serde_json::json;
+
+    use super::{
+        check_canonical, check_known, detect_reorg, BlockHashes, BlockReorged, MAX_REORG_DEPTH,
+    };
+    use crate::test_utils::mock_rpc;
+
+    /// A chain that forked after block `fork`, the blocks above it have other hashes now.
+    async fn forked_chain(fork: u64) -> Provider<Http> {
+        let hash = move |n: u64| match n {
+            n if n > fork => H256::from_low_u64_be(0x4000 + n),
+            n => old_hash(n),
+        };
+        let url = mock_rpc(move |method, params| match method {
+            "eth_getBlockByNumber" => {
+                let n =
+                    u64::from_str_radix(params[0].as_str().unwrap().trim_start_matches("0x"), 16)
+                        .unwrap();
+                Ok(json!({
+                    "number": format!("{n:#x}"),
+                    "hash": hash(n),
+                    "parentHash": hash(n - 1),
+                }))
+            }
+            method => Err(format!("unexpected method {method}")),
+        })
+        .await;
+        Provider::<Http>::try_from(url).unwrap()
+    }
+
+    /// Hash of block `n` before the fork.
+    fn old_hash(n: u64) -> H256 {
+        H256::from_low_u64_be(0x3000 + n)
+    }
+
+    #[test]
+    fn block_hashes_window() {
+        let mut hashes = BlockHashes::new(3);
+        for n in 1..=5u64 {
+            hashes.insert(n, H256::from_low_u64_be(n));
+        }
+        assert_eq!(hashes.lowest(), Some(3));
+        assert_eq!(hashes.get(2), None);
+        assert_eq!(hashes.get(5), Some(H256::from_low_u64_be(5)));
+
+        hashes.remove_above(3);
+        assert_eq!(
+            hashes.iter_desc().map(|(n, _)| n).collect::<Vec<_>>(),
+            vec![3]
+        );
+    }
+
+    #[tokio::test]
+    async fn canonical_logs() {
+        let (provider, _mock) = Provider::mocked();
+        let hash = H256::from_low_u64_be;
+        let known = BTreeMap::from([(10, hash(10)), (11, hash(11))]);
+
+        let logs = [(10, hash(10)), (11, hash(11)), (10, hash(10))];
+        check_canonical(&provider, logs, &known, 2).await.unwrap();
+        assert_eq!(
+            check_known([(10, hash(10)), (12, hash(12))], &known).unwrap(),
+            BTreeMap::from([(12, hash(12))])
+        );
+
+        let error = check_canonical(&provider, [(11, hash(12))], &known, 2)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<BlockReorged>().unwrap().block_number,
+            11
+        );
+
+        let error = check_canonical(&provider, [(10, hash(10)), (10, hash(9))], &known, 2)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<BlockReorged>().is_some());
+    }
+
+    #[tokio::test]
+    async fn find_fork_point() {
+        let provider = forked_chain(112).await;
+        let mut hashes = BlockHashes::new(10);
+        for n in [100, 110, 120] {
+            hashes.insert(n, old_hash(n));
+        }
+
+        // Block 110 is still canonical.
+        assert_eq!(detect_reorg(&provider, &hashes, 110).await.unwrap(), None);
+        assert_eq!(
+            detect_reorg(&provider, &hashes, 120).await.unwrap(),
+            Some(110)
+        );
+    }
+
+    #[tokio::test]
+    async fn fork_below_known_blocks() {
+        let provider = forked_chain(112).await;
+        // After a restart only the last block is known.
+        let mut hashes = BlockHashes::new(10);
+        hashes.insert(120, old_hash(120));
+
+        assert_eq!(
+            detect_reorg(&provider, &hashes, 120).await.unwrap(),
+            Some(120 - MAX_REORG_DEPTH)
+        );
+        // Unknown blocks can't be checked.
+        assert_eq!(detect_reorg(&provider, &hashes, 121).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,289 @@
+use std::{collections::HashMap, sync::atomic::Ordering};
+
+use ethers::{
+    providers::{JsonRpcClient, JsonRpcError},
+    types::{Transaction, H256},
+};
+use futures::{stream, StreamExt, TryStreamExt};
+use serde_json::{json, Value};
+use tracing::warn;
+
+use super::{retry::is_retriable, Response, RpcClient, RpcClientError};
+
+/// Maximum number of calls sent in one JSON-RPC batch.
+const BATCH_SIZE: usize = 100;
+
+/// Lowercase fragments, next to "batch", of the errors nodes answer batches with when they
+/// don't support them.
+const BATCH_UNSUPPORTED: [&str; 4] = ["not supported", "unsupported", "not allowed", "disabled"];
+
+/// Whether the node answered a batch with a clear refusal to handle batches.
+fn is_batch_unsupported(error: &JsonRpcError) -> bool {
+    let message = error.message.to_lowercase();
+    message.contains("batch")
+        && BATCH_UNSUPPORTED
+            .iter()
+            .any(|unsupported| message.contains(unsupported))
+}
+
+impl RpcClient {
+    /// Fetch every transaction of `hashes` once, in as few requests as possible.
+    ///
+    /// Transactions are fetched with JSON-RPC batch requests, which are retried and failed over
+    /// as a whole like single calls. Entries of a batch answered with an error are fetched again
+    /// with single requests, and so is everything once an endpoint refuses batches.
+    pub async fn get_transactions(
+        &self,
+        hashes: &[H256],
+        parallelism: usize,
+    ) -> anyhow::Result<HashMap<H256, Transaction>> {
+        let mut transactions = HashMap::with_capacity(hashes.len());
+        let mut missing = Vec::new();
+        for chunk in hashes.chunks(BATCH_SIZE) {
+            if !self.batch_supported.load(Ordering::Relaxed) {
+                missing.extend_from_slice(chunk);
+                continue;
+            }
+            match self.batch_get_transactions(chunk).await {
+                Ok(results) => {
+                    for (hash, result) in chunk.iter().zip(results) {
+                        match result {
+                            Some(transaction) => {
+                                transactions.insert(*hash, transaction);
+                            }
+                            None => missing.push(*hash),
+                        }
+                    }
+                }
+                Err(RpcClientError::JsonRpc(e)) if is_batch_unsupported(&e) => {
+                    warn!("Batch requests are not supported by the rpc, sending single requests instead: {e}");
+                    self.batch_supported.store(false, Ordering::Relaxed);
+                    missing.extend_from_slice(chunk);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let fetched = stream::iter(missing)
+            .map(|hash| async move {
+                let transaction = self
+                    .request::<_, Option<Transaction>>("eth_getTransactionByHash", [hash])
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("Transaction {hash:?} should exist"))?;
+                anyhow::Ok((hash, transaction))
+            })
+            .buffer_unordered(parallelism)
+            .try_collect::<Vec<_>>()
+            .await?;
+        transactions.extend(fetched);
+        Ok(transactions)
+    }
+
+    /// Send one `eth_getTransactionByHash` batch, retried following the `RetryPolicy`. Entries
+    /// answered with an error come back as `None` so they can be retried on their own.
+    async fn batch_get_transactions(
+        &self,
+        hashes: &[H256],
+    ) -> Result<Vec<Option<Transaction>>, RpcClientError> {
+        let requests: Vec<Value> = hashes
+            .iter()
+            .enumerate()
+            .map(|(id, hash)| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "method": "eth_getTransactionByHash",
+                    "params": [hash],
+                })
+            })
+            .collect();
+        let batch = Value::Array(requests);
+        let mut attempt = 1;
+        let responses = loop {
+            match self.batch_failover(&batch, hashes.len() as u32).await {
+                // A garbled answer, like an error page of a proxy, is worth another try here.
+                Err(e)
+                    if attempt < self.retry.max_attempts
+                        && (is_retriable(&e) || matches!(e, RpcClientError::Serde(_))) =>
+                {
+                    let backoff = self.retry.backoff(attempt);
+                    warn!("Batch of {} calls failed on attempt {attempt}, retrying in {backoff:?}: {e}", hashes.len());
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => break result?,
+            }
+        };
+
+        let mut results = vec![None; hashes.len()];
+        for response in responses {
+            let id = response["id"].as_u64().map(|id| id as usize);
+            if let Some(slot) = id.and_then(|id| results.get_mut(id)) {
+                *slot = serde_json::from_value(response["result"].clone()).ok();
+            }
+        }
+        Ok(results)
+    }
+
+    /// Send `batch` of `calls` calls to the endpoints in order until one of them answers it,
+    /// like `failover` does for single calls.
+    async fn batch_failover(
+        &self,
+        batch: &Value,
+        calls: u32,
+    ) -> Result<Vec<Value>, RpcClientError> {
+        let mut last_error = None;
+        for endpoint in self.ordered() {
+            if let Some(limiter) = &self.limiter {
+                let cost = limiter.cost("eth_getTransactionByHash");
+                limiter.acquire(cost * calls).await;
+            }
+            let result = match self.send(endpoint, batch).await {
+                Ok(Value::Array(responses)) => Ok(responses),
+                // Errors about the whole batch come as a single response.
+                Ok(other) => match serde_json::from_value::<Response>(other.clone()) {
+                    Ok(Response {
+                        error: Some(error), ..
+                    }) => Err(error.into()),
+                    _ => Err(RpcClientError::Serde(serde::de::Error::custom(format!(
+                        "expected a batch response, got {other}"
+                    )))),
+                },
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(responses) => {
+                    endpoint.record_success();
+                    return Ok(responses);
+                }
+                Err(e @ RpcClientError::JsonRpc(_)) if !is_retriable(&e) => {
+                    endpoint.record_success();
+                    return Err(e);
+                }
+                Err(e) => {
+                    endpoint.record_failure(&e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("At least one rpc url is configured"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use ethers::types::{Transaction, H256};
+    use serde_json::{json, Value};
+
+    use crate::{
+        rpc::{RetryPolicy, RpcClient},
+        test_utils::{mock_rpc, mock_server, Reply},
+    };
+
+    fn client(url: String) -> RpcClient {
+        let retry = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        };
+        RpcClient::new(
+            vec![url.parse().unwrap()],
+            Duration::from_secs(5),
+            1,
+            retry,
+            None,
+        )
+    }
+
+    fn transaction(request: &Value) -> Value {
+        let hash: H256 = serde_json::from_value(request["params"][0].clone()).unwrap();
+        let transaction = Transaction {
+            hash,
+            ..Default::default()
+        };
+        json!({"jsonrpc": "2.0", "id": request["id"], "result": transaction})
+    }
+
+    #[tokio::test]
+    async fn get_transactions_in_batch() {
+        let url = mock_rpc(|method, params| match method {
+            "eth_getTransactionByHash" => {
+                let hash: H256 = serde_json::from_value(params[0].clone()).unwrap();
+                let transaction = Transaction {
+                    hash,
+                    ..Default::default()
+                };
+                Ok(serde_json::to_value(transaction).unwrap())
+            }
+            method => Err(format!("unexpected method {method}")),
+        })
+        .await;
+        let client = client(url);
+
+        let hashes = [H256::from_low_u64_be(1), H256::from_low_u64_be(2)];
+        let transactions = client.get_transactions(&hashes, 2).await.unwrap();
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[&hashes[1]].hash, hashes[1]);
+    }
+
+    #[tokio::test]
+    async fn retry_throttled_batch() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let url =
+            mock_server(
+                move |request| match (counter.fetch_add(1, Ordering::SeqCst), request) {
+                    (0, _) => Reply {
+                        status: 429,
+                        body: "Too Many Requests".to_string(),
+                        delay: Duration::ZERO,
+                    },
+                    // Not json at all, but with a success status.
+                    (1, _) => Reply {
+                        status: 200,
+                        body: "<html>Bad gateway</html>".to_string(),
+                        delay: Duration::ZERO,
+                    },
+                    (_, Value::Array(batch)) => {
+                        Reply::json(Value::Array(batch.iter().map(transaction).collect()))
+                    }
+                    (_, request) => panic!("unexpected single request {request}"),
+                },
+            )
+            .await;
+        let client = client(url);
+
+        let hashes = [H256::from_low_u64_be(1), H256::from_low_u64_be(2)];
+        let transactions = client.get_transactions(&hashes, 2).await.unwrap();
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert!(client.batch_supported.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn single_requests_when_batches_are_refused() {
+        let url = mock_server(|request| match request {
+            Value::Array(_) => Reply::json(json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": {"code": -32600, "message": "Batch requests are not supported"}
+            })),
+            request => Reply::json(transaction(request)),
+        })
+        .await;
+        let client = client(url);
+
+        let hashes = [H256::from_low_u64_be(1), H256::from_low_u64_be(2)];
+        let transactions = client.get_transactions(&hashes, 2).await.unwrap();
+        assert_eq!(transactions[&hashes[0]].hash, hashes[0]);
+        assert!(!client.batch_supported.load(Ordering::Relaxed));
+    }
+}
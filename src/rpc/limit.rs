@@ -0,0 +1,135 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Compute units of one rpc method, parsed from `<method>=<units>`.
+#[derive(Clone, Debug)]
+pub struct MethodCost {
+    pub method: String,
+    pub units: u32,
+}
+
+impl FromStr for MethodCost {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (method, units) = value
+            .split_once('=')
+            .ok_or_else(|| "expected <method>=<compute units>".to_string())?;
+        Ok(Self {
+            method: method.to_string(),
+            units: units.parse().map_err(|e| format!("{e}"))?,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    units: f64,
+    refilled_at: Instant,
+}
+
+/// Token bucket shared by every rpc call.
+///
+/// The bucket holds one second worth of compute units and refills continuously. Methods
+/// without a configured cost take one unit, so without costs the rate is in requests per second.
+#[derive(Debug)]
+pub struct RateLimiter {
+    units_per_second: f64,
+    costs: HashMap<String, u32>,
+    bucket: Mutex<Bucket>,
+    used: AtomicU64,
+    started_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(units_per_second: u32, costs: Vec<MethodCost>) -> Self {
+        let units_per_second = f64::from(units_per_second.max(1));
+        Self {
+            units_per_second,
+            costs: costs
+                .into_iter()
+                .map(|cost| (cost.method, cost.units))
+                .collect(),
+            bucket: Mutex::new(Bucket {
+                units: units_per_second,
+                refilled_at: Instant::now(),
+            }),
+            used: AtomicU64::new(0),
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn cost(&self, method: &str) -> u32 {
+        self.costs.get(method).copied().unwrap_or(1)
+    }
+
+    /// Wait until `units` compute units are available and take them. Calls costing more than
+    /// the bucket holds wait for a full bucket and leave it in debt.
+    pub async fn acquire(&self, units: u32) {
+        let units = f64::from(units);
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let refill = (now - bucket.refilled_at).as_secs_f64() * self.units_per_second;
+                bucket.units = (bucket.units + refill).min(self.units_per_second);
+                bucket.refilled_at = now;
+
+                let needed = units.min(self.units_per_second);
+                if bucket.units >= needed {
+                    bucket.units -= units;
+                    break;
+                }
+                Duration::from_secs_f64((needed - bucket.units) / self.units_per_second)
+            };
+            tokio::time::sleep(wait).await;
+        }
+        self.used.fetch_add(units as u64, Ordering::Relaxed);
+    }
+
+    /// Compute units used so far and the average per second since the start.
+    pub fn usage(&self) -> (u64, f64) {
+        let used = self.used.load(Ordering::Relaxed);
+        let elapsed = self.started_at.elapsed().as_secs_f64().max(1.0);
+        (used, used as f64 / elapsed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{MethodCost, RateLimiter};
+
+    #[test]
+    fn parse_method_cost() {
+        let cost: MethodCost = "eth_getLogs=75".parse().unwrap();
+        assert_eq!(cost.method, "eth_getLogs");
+        assert_eq!(cost.units, 75);
+        assert!("eth_getLogs".parse::<MethodCost>().is_err());
+    }
+
+    #[tokio::test]
+    async fn wait_for_budget() {
+        let limiter = RateLimiter::new(100, vec!["eth_getLogs=75".parse().unwrap()]);
+        assert_eq!(limiter.cost("eth_getLogs"), 75);
+        assert_eq!(limiter.cost("eth_chainId"), 1);
+
+        let started = Instant::now();
+        limiter.acquire(limiter.cost("eth_getLogs")).await;
+        limiter.acquire(25).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        // The bucket is empty, 20 units take about 200ms to refill.
+        limiter.acquire(20).await;
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert_eq!(limiter.usage().0, 120);
+    }
+}
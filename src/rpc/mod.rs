@@ -0,0 +1,488 @@
+mod batch;
+mod limit;
+mod retry;
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use ethers::{
+    providers::{JsonRpcClient, JsonRpcError, Provider, ProviderError, RpcError},
+    types::{Address, Bytes, Log, H256, U256},
+};
+use futures::future::join_all;
+use reqwest::{Client, StatusCode, Url};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use self::retry::is_retriable;
+pub use self::{
+    limit::{MethodCost, RateLimiter},
+    retry::RetryPolicy,
+};
+
+/// Provider used for every http call of the indexer.
+pub type RpcProvider = Provider<RpcClient>;
+
+/// How long an endpoint is put behind the healthy ones after it failed.
+const UNHEALTHY_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// An rpc url and its priority, lower priorities are tried first.
+#[derive(Clone, Debug)]
+pub struct RpcEndpoint {
+    pub url: Url,
+    pub priority: u32,
+}
+
+impl FromStr for RpcEndpoint {
+    type Err = String;
+
+    /// Parse `[<priority>:]<url>`, the priority defaults to 0.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (priority, url) = match value.split_once(':') {
+            Some((priority, url)) if priority.chars().all(|c| c.is_ascii_digit()) => {
+                (priority.parse::<u32>().map_err(|e| e.to_string())?, url)
+            }
+            _ => (0, value),
+        };
+        let url = Url::parse(url).map_err(|e| e.to_string())?;
+        Ok(Self { url, priority })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RpcClientError {
+    #[error(transparent)]
+    JsonRpc(#[from] JsonRpcError),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("Http status {0}: {1}")]
+    Status(StatusCode, String),
+    #[error("Invalid response: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("Request timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("{0}")]
+    Quorum(String),
+}
+
+impl RpcError for RpcClientError {
+    fn as_error_response(&self) -> Option<&JsonRpcError> {
+        match self {
+            RpcClientError::JsonRpc(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn as_serde_error(&self) -> Option<&serde_json::Error> {
+        match self {
+            RpcClientError::Serde(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<RpcClientError> for ProviderError {
+    fn from(value: RpcClientError) -> Self {
+        ProviderError::JsonRpcClientError(Box::new(value))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    failures: u32,
+    unhealthy_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    url: Url,
+    health: Mutex<Health>,
+}
+
+impl Endpoint {
+    fn is_healthy(&self) -> bool {
+        let health = self.health.lock().unwrap();
+        health
+            .unhealthy_until
+            .is_none_or(|until| Instant::now() >= until)
+    }
+
+    fn record_success(&self) {
+        let mut health = self.health.lock().unwrap();
+        if health.failures > 0 {
+            info!(
+                "Rpc {} is healthy again after {} failures",
+                self.url, health.failures
+            );
+        }
+        *health = Health::default();
+    }
+
+    fn record_failure(&self, error: &RpcClientError) {
+        let mut health = self.health.lock().unwrap();
+        health.failures += 1;
+        health.unhealthy_until = Some(Instant::now() + UNHEALTHY_COOLDOWN);
+        warn!(
+            "Rpc {} failed {} times in a row: {error}",
+            self.url, health.failures
+        );
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    #[serde(default)]
+    result: Value,
+    error: Option<JsonRpcError>,
+}
+
+/// JSON-RPC client over several http endpoints.
+///
+/// Requests go to the healthy endpoint with the lowest priority and fail over to the next one
+/// on transport errors and timeouts. Errors answered by the node itself are returned as they
+/// are. With a quorum above 1, `eth_getLogs` is sent to every endpoint and only succeeds when
+/// enough of them return the same logs.
+///
+/// Calls failing with a retriable error are sent again following the `RetryPolicy`, and every
+/// request waits for its compute units when a `RateLimiter` is set.
+#[derive(Debug)]
+pub struct RpcClient {
+    client: Client,
+    endpoints: Vec<Endpoint>,
+    timeout: Duration,
+    quorum: usize,
+    retry: RetryPolicy,
+    limiter: Option<RateLimiter>,
+    next_id: AtomicU64,
+    batch_supported: AtomicBool,
+}
+
+impl RpcClient {
+    pub fn new(
+        mut endpoints: Vec<RpcEndpoint>,
+        timeout: Duration,
+        quorum: usize,
+        retry: RetryPolicy,
+        limiter: Option<RateLimiter>,
+    ) -> Self {
+        endpoints.sort_by_key(|endpoint| endpoint.priority);
+        Self {
+            client: Client::new(),
+            endpoints: endpoints
+                .into_iter()
+                .map(|endpoint| Endpoint {
+                    url: endpoint.url,
+                    health: Mutex::new(Health::default()),
+                })
+                .collect(),
+            timeout,
+            quorum: quorum.max(1),
+            retry,
+            limiter,
+            next_id: AtomicU64::new(1),
+            batch_supported: AtomicBool::new(true),
+        }
+    }
+
+    /// Compute units used so far and the average per second, if the client is rate limited.
+    pub fn usage(&self) -> Option<(u64, f64)> {
+        self.limiter.as_ref().map(RateLimiter::usage)
+    }
+
+    /// Endpoints in the order they should be tried: healthy ones by priority, then the others.
+    fn ordered(&self) -> Vec<&Endpoint> {
+        let (mut healthy, unhealthy): (Vec<_>, Vec<_>) = self
+            .endpoints
+            .iter()
+            .partition(|endpoint| endpoint.is_healthy());
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    /// Post `body` to `endpoint` and return the decoded response body.
+    async fn send(&self, endpoint: &Endpoint, body: &Value) -> Result<Value, RpcClientError> {
+        let response = tokio::time::timeout(self.timeout, async {
+            let response = self
+                .client
+                .post(endpoint.url.clone())
+                .json(body)
+                .send()
+                .await?;
+            let status = response.status();
+            let bytes = response.bytes().await?;
+            match serde_json::from_slice(&bytes) {
+                Ok(value) => Ok(value),
+                Err(_) if !status.is_success() => Err(RpcClientError::Status(
+                    status,
+                    String::from_utf8_lossy(&bytes).into_owned(),
+                )),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await
+        .map_err(|_| RpcClientError::Timeout(self.timeout))??;
+        Ok(response)
+    }
+
+    async fn call(
+        &self,
+        endpoint: &Endpoint,
+        method: &str,
+        params: &Value,
+    ) -> Result<Value, RpcClientError> {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(limiter.cost(method)).await;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut request = json!({"jsonrpc": "2.0", "id": id, "method": method});
+        if !params.is_null() {
+            request["params"] = params.clone();
+        }
+        let response: Response = serde_json::from_value(self.send(endpoint, &request).await?)?;
+        match response.error {
+            Some(error) => Err(error.into()),
+            None => Ok(response.result),
+        }
+    }
+
+    async fn request_value(&self, method: &str, params: Value) -> Result<Value, RpcClientError> {
+        let mut attempt = 1;
+        loop {
+            let result = if self.quorum > 1 && method == "eth_getLogs" {
+                self.quorum_request(method, &params).await
+            } else {
+                self.failover(method, &params).await
+            };
+            match result {
+                Err(e) if attempt < self.retry.max_attempts && is_retriable(&e) => {
+                    let backoff = self.retry.backoff(attempt);
+                    warn!("Rpc call {method} failed on attempt {attempt}, retrying in {backoff:?}: {e}");
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send the request to the endpoints in order until one of them answers.
+    async fn failover(&self, method: &str, params: &Value) -> Result<Value, RpcClientError> {
+        let mut last_error = None;
+        for endpoint in self.ordered() {
+            match self.call(endpoint, method, params).await {
+                Ok(result) => {
+                    endpoint.record_success();
+                    return Ok(result);
+                }
+                Err(e @ RpcClientError::JsonRpc(_)) if !is_retriable(&e) => {
+                    endpoint.record_success();
+                    return Err(e);
+                }
+                Err(e) => {
+                    endpoint.record_failure(&e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("At least one rpc url is configured"))
+    }
+
+    /// Send the request to every endpoint and return the result at least `quorum` of them
+    /// agree on. Logs are compared by what gets indexed from them, so answers differing only in
+    /// formatting or in fields some nodes leave out still agree.
+    async fn quorum_request(&self, method: &str, params: &Value) -> Result<Value, RpcClientError> {
+        let results = join_all(
+            self.endpoints
+                .iter()
+                .map(|endpoint| self.call(endpoint, method, params)),
+        )
+        .await;
+
+        let mut votes: HashMap<Vec<LogProjection>, (Value, usize)> = HashMap::new();
+        let mut node_error = None;
+        for (endpoint, result) in self.endpoints.iter().zip(results) {
+            match result.and_then(|value| Ok((project_logs(&value)?, value))) {
+                Ok((projection, value)) => {
+                    endpoint.record_success();
+                    votes.entry(projection).or_insert((value, 0)).1 += 1;
+                }
+                Err(e @ RpcClientError::JsonRpc(_)) if !is_retriable(&e) => {
+                    endpoint.record_success();
+                    node_error.get_or_insert(e);
+                }
+                Err(e) => endpoint.record_failure(&e),
+            }
+        }
+
+        let best = votes.into_values().max_by_key(|(_, count)| *count);
+        match (best, node_error) {
+            (Some((value, count)), _) if count >= self.quorum => Ok(value),
+            (None, Some(e)) => Err(e),
+            (best, _) => Err(RpcClientError::Quorum(format!(
+                "Only {} of {} rpc endpoints agreed on {method}, {} required",
+                best.map_or(0, |(_, count)| count),
+                self.endpoints.len(),
+                self.quorum
+            ))),
+        }
+    }
+}
+
+/// The fields of a log the indexer reads: block hash, transaction hash, log index, address,
+/// topics and data.
+type LogProjection = (
+    Option<H256>,
+    Option<H256>,
+    Option<U256>,
+    Address,
+    Vec<H256>,
+    Bytes,
+);
+
+/// Projection of every log of an `eth_getLogs` answer.
+fn project_logs(value: &Value) -> Result<Vec<LogProjection>, RpcClientError> {
+    let logs: Vec<Log> = serde_json::from_value(value.clone())?;
+    Ok(logs
+        .into_iter()
+        .map(|log| {
+            (
+                log.block_hash,
+                log.transaction_hash,
+                log.log_index,
+                log.address,
+                log.topics,
+                log.data,
+            )
+        })
+        .collect())
+}
+
+#[async_trait]
+impl JsonRpcClient for RpcClient {
+    type Error = RpcClientError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: std::fmt::Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let params = serde_json::to_value(params)?;
+        let result = self.request_value(method, params).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use ethers::providers::{JsonRpcClient, Middleware, Provider};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    use super::{RetryPolicy, RpcClient, RpcClientError, RpcEndpoint};
+    use crate::test_utils::mock_rpc;
+
+    /// Url of a local port nothing listens on.
+    async fn dead_url() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    fn client(urls: &[String], quorum: usize) -> RpcClient {
+        let endpoints = urls
+            .iter()
+            .enumerate()
+            .map(|(priority, url)| format!("{priority}:{url}").parse().unwrap())
+            .collect::<Vec<RpcEndpoint>>();
+        let retry = RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        };
+        RpcClient::new(endpoints, Duration::from_secs(5), quorum, retry, None)
+    }
+
+    #[test]
+    fn parse_endpoint() {
+        let endpoint: RpcEndpoint = "2:https://rpc.example.com/v2/key".parse().unwrap();
+        assert_eq!(endpoint.priority, 2);
+        assert_eq!(endpoint.url.as_str(), "https://rpc.example.com/v2/key");
+
+        let endpoint: RpcEndpoint = "http://localhost:8545".parse().unwrap();
+        assert_eq!(endpoint.priority, 0);
+        assert_eq!(endpoint.url.port(), Some(8545));
+    }
+
+    #[tokio::test]
+    async fn failover_to_next_endpoint() {
+        let dead = dead_url().await;
+        let live = mock_rpc(|method, _| match method {
+            "eth_blockNumber" => Ok(json!("0x10")),
+            method => Err(format!("unexpected method {method}")),
+        })
+        .await;
+        let provider = Provider::new(client(&[dead.clone(), live.clone()], 1));
+
+        assert_eq!(provider.get_block_number().await.unwrap().as_u64(), 16);
+        // The dead endpoint is now tried last despite its priority.
+        let ordered = provider.as_ref().ordered();
+        assert_eq!(ordered[0].url.as_str(), format!("{live}/"));
+        assert_eq!(ordered[1].url.as_str(), format!("{dead}/"));
+    }
+
+    #[tokio::test]
+    async fn node_errors_are_not_retried_elsewhere() {
+        let failing = mock_rpc(|_, _| Err("execution reverted".to_string())).await;
+        let live = mock_rpc(|_, _| Ok(json!("0x1"))).await;
+        let client = client(&[failing, live], 1);
+
+        let result = client.request::<_, Value>("eth_call", ()).await;
+        assert!(matches!(result, Err(RpcClientError::JsonRpc(_))));
+    }
+
+    #[tokio::test]
+    async fn get_logs_quorum() {
+        let log = |log_index: &str| {
+            json!({
+                "address": "0x5ff137d4b0fdcd49dca30c7cf57e578a026d2789",
+                "topics": ["0x49628fd1471006c1482da88028e9ce4dbb080b815c9b0344d39e5a8e6ec1419f"],
+                "data": "0x",
+                "blockHash": "0x0000000000000000000000000000000000000000000000000000000000000001",
+                "blockNumber": "0x10",
+                "transactionHash": "0x0000000000000000000000000000000000000000000000000000000000000002",
+                "logIndex": log_index,
+            })
+        };
+        let first = mock_rpc(move |_, _| Ok(json!([log("0x1")]))).await;
+        // Same log, with a field the other node leaves out and another number formatting.
+        let second = mock_rpc(move |_, _| {
+            let mut log = log("0x01");
+            log["removed"] = json!(false);
+            Ok(json!([log]))
+        })
+        .await;
+        let other = mock_rpc(|_, _| Ok(json!([]))).await;
+
+        let agreed = client(&[first.clone(), second, other.clone()], 2)
+            .request::<_, Value>("eth_getLogs", [json!({})])
+            .await
+            .unwrap();
+        assert_eq!(agreed, json!([log("0x1")]));
+
+        let disagreed = client(&[first, other], 2)
+            .request::<_, Value>("eth_getLogs", [json!({})])
+            .await;
+        assert!(matches!(disagreed, Err(RpcClientError::Quorum(_))));
+    }
+}
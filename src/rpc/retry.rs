@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::StatusCode;
+
+use super::RpcClientError;
+use crate::range::is_range_limit_error;
+
+/// JSON-RPC error codes providers use for rate limiting.
+const RATE_LIMIT_CODES: [i64; 2] = [429, -32005];
+
+/// Lowercase fragments of node errors that go away on their own.
+const TRANSIENT_ERRORS: [&str; 7] = [
+    "rate limit",
+    "too many requests",
+    "rate exceeded",
+    "limit exceeded",
+    "timeout",
+    "header not found",
+    "temporarily unavailable",
+];
+
+/// How failed rpc calls are retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Attempts per call, the first one included.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Backoff before the attempt following `attempt`, doubled every attempt up to the
+    /// maximum, and randomized between half and the full value so concurrent calls don't
+    /// retry in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        let half = backoff / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// Whether `error` may succeed when the same call is sent again.
+pub fn is_retriable(error: &RpcClientError) -> bool {
+    match error {
+        RpcClientError::Http(_) | RpcClientError::Timeout(_) | RpcClientError::Quorum(_) => true,
+        RpcClientError::Status(status, _) => {
+            *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+        }
+        // Ranges refused as too large are shrunk by the caller instead.
+        RpcClientError::JsonRpc(e) if is_range_limit_error(&e.message) => false,
+        RpcClientError::JsonRpc(e) => {
+            let message = e.message.to_lowercase();
+            RATE_LIMIT_CODES.contains(&e.code)
+                || TRANSIENT_ERRORS
+                    .iter()
+                    .any(|transient| message.contains(transient))
+        }
+        RpcClientError::Serde(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use ethers::providers::{JsonRpcClient, JsonRpcError};
+    use reqwest::StatusCode;
+    use serde_json::{json, Value};
+
+    use super::{is_retriable, RetryPolicy};
+    use crate::{
+        rpc::{RpcClient, RpcClientError},
+        test_utils::mock_rpc,
+    };
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(40),
+        }
+    }
+
+    fn node_error(code: i64, message: &str) -> RpcClientError {
+        RpcClientError::JsonRpc(JsonRpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        })
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = policy();
+        let first = policy.backoff(1);
+        assert!(first >= Duration::from_millis(5) && first <= Duration::from_millis(10));
+        let capped = policy.backoff(10);
+        assert!(capped >= Duration::from_millis(20) && capped <= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn retriable_errors() {
+        assert!(is_retriable(&RpcClientError::Status(
+            StatusCode::TOO_MANY_REQUESTS,
+            String::new()
+        )));
+        assert!(is_retriable(&node_error(-32005, "limit exceeded")));
+        assert!(is_retriable(&node_error(
+            -32005,
+            "daily request count limited to 100000"
+        )));
+        assert!(is_retriable(&node_error(-32000, "header not found")));
+        assert!(!is_retriable(&RpcClientError::Status(
+            StatusCode::UNAUTHORIZED,
+            String::new()
+        )));
+        assert!(!is_retriable(&node_error(3, "execution reverted")));
+        assert!(!is_retriable(&node_error(
+            -32005,
+            "query returned more than 10000 results"
+        )));
+    }
+
+    #[tokio::test]
+    async fn retry_rate_limited_calls() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let url = mock_rpc(move |_, _| match counter.fetch_add(1, Ordering::SeqCst) {
+            0 | 1 => Err("rate limit reached".to_string()),
+            _ => Ok(json!("0x1")),
+        })
+        .await;
+        let client = RpcClient::new(
+            vec![url.parse().unwrap()],
+            Duration::from_secs(5),
+            1,
+            policy(),
+            None,
+        );
+
+        let result: Value = client.request("eth_blockNumber", ()).await.unwrap();
+        assert_eq!(result, json!("0x1"));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,40 @@
+use tokio::{signal, sync::watch};
+use tracing::{info, warn};
+
+/// Exit status when a second signal interrupts the graceful shutdown, like a shell reports a
+/// process killed by SIGINT.
+const FORCED_EXIT_CODE: i32 = 130;
+
+/// Wait for SIGINT or SIGTERM and publish `true` once one arrives. The indexer then finishes the
+/// range it is committing and stops. A second signal exits right away.
+pub fn spawn_shutdown_listener() -> watch::Receiver<bool> {
+    let (tx, rx) = watch::channel(false);
+    tokio::spawn(async move {
+        if let Err(e) = wait_for_signal().await {
+            warn!("Could not listen for shutdown signals: {e}");
+            return;
+        }
+        info!("Shutdown requested, finishing the current range");
+        tx.send_replace(true);
+
+        if wait_for_signal().await.is_ok() {
+            warn!("Second shutdown signal received, exiting without waiting");
+            std::process::exit(FORCED_EXIT_CODE);
+        }
+    });
+    rx
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> std::io::Result<()> {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        result = signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> std::io::Result<()> {
+    signal::ctrl_c().await
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use ethers::{
+    providers::{Middleware, Provider, StreamExt, Ws},
+    types::{Address, Filter},
+};
+use tokio::{sync::watch, time};
+use tracing::{info, warn};
+
+use crate::{cli::SubscriptionKind, uo::EntryPointEvent};
+
+const RECONNECT_INTERVAL_MILLI: u64 = 5000;
+
+/// Keep a WebSocket subscription open on `url` and publish the block number of every new head
+/// (or EntryPoint log). The socket is reconnected when it drops, the main loop keeps polling on
+/// its interval in the meantime.
+pub fn spawn_subscription(
+    url: String,
+    kind: SubscriptionKind,
+    entry_points: Vec<Address>,
+) -> watch::Receiver<u64> {
+    let (tx, rx) = watch::channel(0u64);
+    tokio::spawn(async move {
+        loop {
+            match subscribe(&url, kind, &entry_points, &tx).await {
+                Ok(()) => warn!("WebSocket subscription on {url} ended, falling back to polling"),
+                Err(e) => {
+                    warn!("WebSocket subscription on {url} failed: {e}, falling back to polling")
+                }
+            }
+            if tx.is_closed() {
+                return;
+            }
+            time::sleep(Duration::from_millis(RECONNECT_INTERVAL_MILLI)).await;
+        }
+    });
+    rx
+}
+
+async fn subscribe(
+    url: &str,
+    kind: SubscriptionKind,
+    entry_points: &[Address],
+    tx: &watch::Sender<u64>,
+) -> anyhow::Result<()> {
+    let provider = Provider::<Ws>::connect(url).await?;
+    match kind {
+        SubscriptionKind::NewHeads => {
+            let mut stream = provider.subscribe_blocks().await?;
+            info!("Subscribed to new heads on {url}");
+            while let Some(block) = stream.next().await {
+                if let Some(number) = block.number {
+                    tx.send_replace(number.as_u64());
+                }
+            }
+        }
+        SubscriptionKind::Logs => {
+            let filter = Filter::new()
+                .address(entry_points.to_vec())
+                .topic0(EntryPointEvent::signatures());
+            let mut stream = provider.subscribe_logs(&filter).await?;
+            info!("Subscribed to EntryPoint logs on {url}");
+            while let Some(log) = stream.next().await {
+                if let Some(number) = log.block_number {
+                    tx.send_replace(number.as_u64());
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use ethers::types::{Address, Bytes, H256, U256};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::uo::{EntryPointVersion, UserOperation, UserOperationData};
+
+type Responder = dyn Fn(&Value) -> Reply + Send + Sync;
+
+/// What a mock server answers to a request body.
+pub struct Reply {
+    pub status: u16,
+    pub body: String,
+    /// How long to wait before answering.
+    pub delay: Duration,
+}
+
+impl Reply {
+    pub fn json(body: Value) -> Self {
+        Self {
+            status: 200,
+            body: body.to_string(),
+            delay: Duration::ZERO,
+        }
+    }
+}
+
+/// Start an http server on a random local port and return its url. `respond` gets the json
+/// body of every request, `null` if it isn't json, and returns the reply.
+pub async fn mock_server<R>(respond: R) -> String
+where
+    R: Fn(&Value) -> Reply + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let respond: Arc<Responder> = Arc::new(respond);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(stream, respond.clone()));
+        }
+    });
+    url
+}
+
+/// Start a JSON-RPC server on a random local port and return its url.
+///
+/// `handler` gets the method and params of every request and returns either the result or an
+/// error message. Batch requests are answered one entry at a time.
+pub async fn mock_rpc<F>(handler: F) -> String
+where
+    F: Fn(&str, &Value) -> Result<Value, String> + Send + Sync + 'static,
+{
+    mock_rpc_delayed(handler, |_, _| Duration::ZERO).await
+}
+
+/// Like [`mock_rpc`], but every request is answered after the duration `delay` returns for its
+/// method and params, or the longest one of its entries for a batch.
+pub async fn mock_rpc_delayed<F, D>(handler: F, delay: D) -> String
+where
+    F: Fn(&str, &Value) -> Result<Value, String> + Send + Sync + 'static,
+    D: Fn(&str, &Value) -> Duration + Send + Sync + 'static,
+{
+    mock_server(move |request| {
+        let requests = match request {
+            Value::Array(requests) => requests.iter().collect(),
+            request => vec![request],
+        };
+        let wait = requests
+            .iter()
+            .map(|request| {
+                delay(
+                    request["method"].as_str().unwrap_or_default(),
+                    &request["params"],
+                )
+            })
+            .max()
+            .unwrap_or_default();
+        let response = match request {
+            Value::Array(_) => Value::Array(
+                requests
+                    .into_iter()
+                    .map(|request| answer(&handler, request))
+                    .collect(),
+            ),
+            request => answer(&handler, request),
+        };
+        Reply {
+            delay: wait,
+            ..Reply::json(response)
+        }
+    })
+    .await
+}
+
+fn answer<F>(handler: &F, request: &Value) -> Value
+where
+    F: Fn(&str, &Value) -> Result<Value, String>,
+{
+    let method = request["method"].as_str().unwrap_or_default();
+    match handler(method, &request["params"]) {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": request["id"], "result": result}),
+        Err(message) => json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "error": {"code": -32000, "message": message}
+        }),
+    }
+}
+
+async fn serve(mut stream: TcpStream, respond: Arc<Responder>) {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let body_start = loop {
+        let n = stream.read(&mut chunk).await.unwrap_or(0);
+        if n == 0 {
+            return;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+    let headers = String::from_utf8_lossy(&buf[..body_start]).to_lowercase();
+    let length = headers
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while buf.len() < body_start + length {
+        let n = stream.read(&mut chunk).await.unwrap_or(0);
+        if n == 0 {
+            return;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request: Value =
+        serde_json::from_slice(&buf[body_start..body_start + length]).unwrap_or(Value::Null);
+    let Reply {
+        status,
+        body,
+        delay,
+    } = respond(&request);
+    time::sleep(delay).await;
+    let reply = format!(
+        "HTTP/1.1 {status} Mock\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = stream.write_all(reply.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// A fresh, empty directory under the system temp directory.
+pub fn temp_dir(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "uoindexer-{name}-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Hash of block `block_number` in the records of the tests.
+pub fn block_hash(block_number: u64) -> H256 {
+    H256::from_low_u64_be(0x3000 + block_number)
+}
+
+/// A stored user operation with nonce `nonce`, included in block `block_number`.
+pub fn user_operation_data(nonce: u64, block_number: u64) -> UserOperationData {
+    UserOperationData {
+        chain_id: 1,
+        entry_point: Address::from_low_u64_be(1),
+        entry_point_version: EntryPointVersion::V0_6,
+        uo: UserOperation {
+            sender: Address::from_low_u64_be(2),
+            nonce: U256::from(nonce),
+            init_code: Bytes::new(),
+            call_data: Bytes::new(),
+            call_gas_limit: U256::zero(),
+            verification_gas_limit: U256::zero(),
+            pre_verification_gas: U256::zero(),
+            max_fee_per_gas: U256::zero(),
+            max_priority_fee_per_gas: U256::zero(),
+            paymaster_and_data: Bytes::new(),
+            signature: Bytes::new(),
+        },
+        uo_hash: H256::from_low_u64_be(0x1000 + nonce),
+        paymaster: Address::zero(),
+        factory: None,
+        success: true,
+        actual_gas_cost: U256::from(21000),
+        actual_gas_price: U256::from(1),
+        transaction_hash: H256::from_low_u64_be(0x2000 + block_number),
+        transaction_index: 0,
+        log_index: nonce,
+        block_number,
+        block_hash: block_hash(block_number),
+        aggregator: None,
+        aggregated_signature: None,
+    }
+}
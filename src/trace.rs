@@ -0,0 +1,39 @@
+use ethers::{
+    providers::Middleware,
+    types::{Address, Bytes, CallFrame, NameOrAddress, H256},
+};
+use serde_json::json;
+
+/// Ask the node for the call tree of `transaction_hash` with the built-in `callTracer`.
+pub async fn trace_call_tree<M: Middleware>(
+    provider: &M,
+    transaction_hash: H256,
+) -> anyhow::Result<CallFrame>
+where
+    M::Error: 'static,
+{
+    let frame = provider
+        .provider()
+        .request(
+            "debug_traceTransaction",
+            (transaction_hash, json!({ "tracer": "callTracer" })),
+        )
+        .await?;
+    Ok(frame)
+}
+
+/// Inputs of every successful call to `to` in the call tree, in execution order.
+pub fn calls_to(frame: &CallFrame, to: Address) -> Vec<Bytes> {
+    let mut inputs = Vec::new();
+    collect_calls(frame, to, &mut inputs);
+    inputs
+}
+
+fn collect_calls(frame: &CallFrame, to: Address, inputs: &mut Vec<Bytes>) {
+    if frame.error.is_none() && frame.to == Some(NameOrAddress::Address(to)) {
+        inputs.push(frame.input.clone());
+    }
+    for call in frame.calls.iter().flatten() {
+        collect_calls(call, to, inputs);
+    }
+}
@@ -1,19 +1,53 @@
 use ethers::{
-    abi::AbiEncode,
+    abi::{AbiEncode, Error as AbiError},
+    contract::parse_log,
     prelude::{EthAbiCodec, EthAbiType, EthDisplay, EthEvent},
-    types::{Address, Bytes, H256, U256},
+    types::{Address, Bytes, Log, H256, U256},
     utils::{keccak256, to_checksum},
 };
 use serde::{Deserialize, Serialize};
 
+/// Version of the EntryPoint contract a user operation was sent to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EntryPointVersion {
+    #[serde(rename = "v0.6")]
+    V0_6,
+    #[serde(rename = "v0.7")]
+    V0_7,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct UserOperationData {
+    /// Records written before the chain id was stored default to 0.
+    #[serde(default)]
+    pub chain_id: u64,
+    pub entry_point: Address,
+    pub entry_point_version: EntryPointVersion,
+    /// The operation in the v0.6 layout, v0.7 operations are unpacked into it.
     pub uo: UserOperation,
     pub uo_hash: H256,
+    pub paymaster: Address,
+    /// Factory deploying the sender, when the operation carries an `init_code`.
+    #[serde(default)]
+    pub factory: Option<Address>,
+    pub success: bool,
+    pub actual_gas_cost: U256,
+    pub actual_gas_price: U256,
     pub transaction_hash: H256,
     pub transaction_index: u64,
+    pub log_index: u64,
     pub block_number: u64,
     pub block_hash: H256,
+    /// Set when the operation was bundled through `handleAggregatedOps`.
+    pub aggregator: Option<Address>,
+    pub aggregated_signature: Option<Bytes>,
+}
+
+impl UserOperationData {
+    /// Unique key of the user operation, `<chain id>-<uo hash>`.
+    pub fn key(&self) -> String {
+        format!("{}-{:?}", self.chain_id, self.uo_hash)
+    }
 }
 
 #[derive(
@@ -36,6 +70,11 @@ pub struct UserOperation {
 }
 
 impl UserOperation {
+    /// Factory address in the first 20 bytes of the `init_code`, if any.
+    pub fn factory(&self) -> Option<Address> {
+        self.init_code.get(..20).map(Address::from_slice)
+    }
+
     pub fn hash(&self) -> H256 {
         let packed = self.pack();
         keccak256(packed).into()
@@ -65,6 +104,82 @@ impl UserOperation {
     }
 }
 
+/// User operation of the v0.7 EntryPoint with the gas fields packed into `bytes32`.
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, EthAbiCodec, EthAbiType,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct PackedUserOperation {
+    #[serde(serialize_with = "as_checksum")]
+    pub sender: Address,
+    pub nonce: U256,
+    pub init_code: Bytes,
+    pub call_data: Bytes,
+    /// `verificationGasLimit` in the high 128 bits, `callGasLimit` in the low 128 bits.
+    pub account_gas_limits: H256,
+    pub pre_verification_gas: U256,
+    /// `maxPriorityFeePerGas` in the high 128 bits, `maxFeePerGas` in the low 128 bits.
+    pub gas_fees: H256,
+    pub paymaster_and_data: Bytes,
+    pub signature: Bytes,
+}
+
+impl PackedUserOperation {
+    pub fn hash(&self) -> H256 {
+        let packed = self.pack();
+        keccak256(packed).into()
+    }
+
+    pub fn pack(&self) -> Vec<u8> {
+        let init_code_hash = keccak256(self.init_code.clone());
+        let call_data_hash = keccak256(self.call_data.clone());
+        let paymaster_and_data_hash = keccak256(self.paymaster_and_data.clone());
+        (
+            self.sender,
+            self.nonce,
+            init_code_hash,
+            call_data_hash,
+            self.account_gas_limits,
+            self.pre_verification_gas,
+            self.gas_fees,
+            paymaster_and_data_hash,
+        )
+            .encode()
+    }
+
+    pub fn uo_hash(&self, entry_point_addr: Address, chain_id: u64) -> H256 {
+        keccak256((self.hash(), entry_point_addr, U256::from(chain_id)).encode()).into()
+    }
+}
+
+/// Split a `bytes32` into its high and low 128 bits.
+fn unpack_u128s(packed: H256) -> (U256, U256) {
+    (
+        U256::from_big_endian(&packed[..16]),
+        U256::from_big_endian(&packed[16..]),
+    )
+}
+
+impl From<PackedUserOperation> for UserOperation {
+    fn from(value: PackedUserOperation) -> Self {
+        let (verification_gas_limit, call_gas_limit) = unpack_u128s(value.account_gas_limits);
+        let (max_priority_fee_per_gas, max_fee_per_gas) = unpack_u128s(value.gas_fees);
+        Self {
+            sender: value.sender,
+            nonce: value.nonce,
+            init_code: value.init_code,
+            call_data: value.call_data,
+            call_gas_limit,
+            verification_gas_limit,
+            pre_verification_gas: value.pre_verification_gas,
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            paymaster_and_data: value.paymaster_and_data,
+            signature: value.signature,
+        }
+    }
+}
+
 fn as_checksum<S>(val: &Address, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
@@ -90,6 +205,226 @@ pub struct UserOperationEvent {
     pub actual_gas_price: ethers::core::types::U256,
 }
 
+#[derive(Clone, Debug, Eq, PartialEq, EthEvent, EthDisplay, Default, Serialize, Deserialize)]
+#[ethevent(
+    name = "AccountDeployed",
+    abi = "AccountDeployed(bytes32,address,address,address)"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountDeployedEvent {
+    #[ethevent(indexed)]
+    pub user_op_hash: H256,
+    #[ethevent(indexed)]
+    pub sender: Address,
+    pub factory: Address,
+    pub paymaster: Address,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, EthEvent, EthDisplay, Default, Serialize, Deserialize)]
+#[ethevent(
+    name = "UserOperationRevertReason",
+    abi = "UserOperationRevertReason(bytes32,address,uint256,bytes)"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperationRevertReasonEvent {
+    #[ethevent(indexed)]
+    pub user_op_hash: H256,
+    #[ethevent(indexed)]
+    pub sender: Address,
+    pub nonce: U256,
+    pub revert_reason: Bytes,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, EthEvent, EthDisplay, Default, Serialize, Deserialize)]
+#[ethevent(
+    name = "PostOpRevertReason",
+    abi = "PostOpRevertReason(bytes32,address,uint256,bytes)"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct PostOpRevertReasonEvent {
+    #[ethevent(indexed)]
+    pub user_op_hash: H256,
+    #[ethevent(indexed)]
+    pub sender: Address,
+    pub nonce: U256,
+    pub revert_reason: Bytes,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, EthEvent, EthDisplay, Default, Serialize, Deserialize)]
+#[ethevent(
+    name = "UserOperationPrefundTooLow",
+    abi = "UserOperationPrefundTooLow(bytes32,address,uint256)"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperationPrefundTooLowEvent {
+    #[ethevent(indexed)]
+    pub user_op_hash: H256,
+    #[ethevent(indexed)]
+    pub sender: Address,
+    pub nonce: U256,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, EthEvent, EthDisplay, Default, Serialize, Deserialize)]
+#[ethevent(name = "BeforeExecution", abi = "BeforeExecution()")]
+pub struct BeforeExecutionEvent {}
+
+#[derive(Clone, Debug, Eq, PartialEq, EthEvent, EthDisplay, Default, Serialize, Deserialize)]
+#[ethevent(name = "Deposited", abi = "Deposited(address,uint256)")]
+#[serde(rename_all = "camelCase")]
+pub struct DepositedEvent {
+    #[ethevent(indexed)]
+    pub account: Address,
+    pub total_deposit: U256,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, EthEvent, EthDisplay, Default, Serialize, Deserialize)]
+#[ethevent(name = "Withdrawn", abi = "Withdrawn(address,address,uint256)")]
+#[serde(rename_all = "camelCase")]
+pub struct WithdrawnEvent {
+    #[ethevent(indexed)]
+    pub account: Address,
+    pub withdraw_address: Address,
+    pub amount: U256,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, EthEvent, EthDisplay, Default, Serialize, Deserialize)]
+#[ethevent(name = "StakeLocked", abi = "StakeLocked(address,uint256,uint256)")]
+#[serde(rename_all = "camelCase")]
+pub struct StakeLockedEvent {
+    #[ethevent(indexed)]
+    pub account: Address,
+    pub total_staked: U256,
+    pub unstake_delay_sec: U256,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, EthEvent, EthDisplay, Default, Serialize, Deserialize)]
+#[ethevent(name = "StakeUnlocked", abi = "StakeUnlocked(address,uint256)")]
+#[serde(rename_all = "camelCase")]
+pub struct StakeUnlockedEvent {
+    #[ethevent(indexed)]
+    pub account: Address,
+    pub withdraw_time: U256,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, EthEvent, EthDisplay, Default, Serialize, Deserialize)]
+#[ethevent(
+    name = "StakeWithdrawn",
+    abi = "StakeWithdrawn(address,address,uint256)"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct StakeWithdrawnEvent {
+    #[ethevent(indexed)]
+    pub account: Address,
+    pub withdraw_address: Address,
+    pub amount: U256,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, EthEvent, EthDisplay, Default, Serialize, Deserialize)]
+#[ethevent(
+    name = "SignatureAggregatorChanged",
+    abi = "SignatureAggregatorChanged(address)"
+)]
+pub struct SignatureAggregatorChangedEvent {
+    #[ethevent(indexed)]
+    pub aggregator: Address,
+}
+
+/// Every EntryPoint event apart from `UserOperationEvent`, which is stored as `UserOperationData`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", content = "args")]
+pub enum EntryPointEvent {
+    AccountDeployed(AccountDeployedEvent),
+    UserOperationRevertReason(UserOperationRevertReasonEvent),
+    /// v0.7 only
+    PostOpRevertReason(PostOpRevertReasonEvent),
+    /// v0.7 only
+    UserOperationPrefundTooLow(UserOperationPrefundTooLowEvent),
+    BeforeExecution(BeforeExecutionEvent),
+    Deposited(DepositedEvent),
+    Withdrawn(WithdrawnEvent),
+    StakeLocked(StakeLockedEvent),
+    StakeUnlocked(StakeUnlockedEvent),
+    StakeWithdrawn(StakeWithdrawnEvent),
+    SignatureAggregatorChanged(SignatureAggregatorChangedEvent),
+}
+
+impl EntryPointEvent {
+    /// Topics of all the events emitted by the EntryPoint, including `UserOperationEvent`.
+    pub fn signatures() -> Vec<H256> {
+        vec![
+            UserOperationEvent::signature(),
+            AccountDeployedEvent::signature(),
+            UserOperationRevertReasonEvent::signature(),
+            PostOpRevertReasonEvent::signature(),
+            UserOperationPrefundTooLowEvent::signature(),
+            BeforeExecutionEvent::signature(),
+            DepositedEvent::signature(),
+            WithdrawnEvent::signature(),
+            StakeLockedEvent::signature(),
+            StakeUnlockedEvent::signature(),
+            StakeWithdrawnEvent::signature(),
+            SignatureAggregatorChangedEvent::signature(),
+        ]
+    }
+
+    /// Decode a log into an event, `None` if the log is not one of the events above.
+    pub fn decode(log: Log) -> Result<Option<Self>, AbiError> {
+        let topic = match log.topics.first() {
+            Some(topic) => *topic,
+            None => return Ok(None),
+        };
+        let event = if topic == AccountDeployedEvent::signature() {
+            Self::AccountDeployed(parse_log(log)?)
+        } else if topic == UserOperationRevertReasonEvent::signature() {
+            Self::UserOperationRevertReason(parse_log(log)?)
+        } else if topic == PostOpRevertReasonEvent::signature() {
+            Self::PostOpRevertReason(parse_log(log)?)
+        } else if topic == UserOperationPrefundTooLowEvent::signature() {
+            Self::UserOperationPrefundTooLow(parse_log(log)?)
+        } else if topic == BeforeExecutionEvent::signature() {
+            Self::BeforeExecution(parse_log(log)?)
+        } else if topic == DepositedEvent::signature() {
+            Self::Deposited(parse_log(log)?)
+        } else if topic == WithdrawnEvent::signature() {
+            Self::Withdrawn(parse_log(log)?)
+        } else if topic == StakeLockedEvent::signature() {
+            Self::StakeLocked(parse_log(log)?)
+        } else if topic == StakeUnlockedEvent::signature() {
+            Self::StakeUnlocked(parse_log(log)?)
+        } else if topic == StakeWithdrawnEvent::signature() {
+            Self::StakeWithdrawn(parse_log(log)?)
+        } else if topic == SignatureAggregatorChangedEvent::signature() {
+            Self::SignatureAggregatorChanged(parse_log(log)?)
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(event))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EntryPointEventData {
+    #[serde(default)]
+    pub chain_id: u64,
+    pub entry_point: Address,
+    pub event: EntryPointEvent,
+    pub transaction_hash: H256,
+    pub transaction_index: u64,
+    pub log_index: u64,
+    pub block_number: u64,
+    pub block_hash: H256,
+}
+
+impl EntryPointEventData {
+    /// Unique key of the event, `<chain id>-<transaction hash>-<log index>`.
+    pub fn key(&self) -> String {
+        format!(
+            "{}-{:?}-{}",
+            self.chain_id, self.transaction_hash, self.log_index
+        )
+    }
+}
+
 #[derive(
     Clone,
     Debug,
@@ -108,13 +443,141 @@ pub struct HandleOpsCall {
     pub beneficiary: Address,
 }
 
+#[derive(
+    Clone,
+    Debug,
+    Eq,
+    PartialEq,
+    ethers :: contract :: EthCall,
+    ethers :: contract :: EthDisplay,
+    Default,
+)]
+#[ethcall(
+    name = "handleOps",
+    abi = "handleOps((address,uint256,bytes,bytes,bytes32,uint256,bytes32,bytes,bytes)[],address)"
+)]
+pub struct HandleOpsV07Call {
+    pub ops: Vec<PackedUserOperation>,
+    pub beneficiary: Address,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, EthAbiCodec, EthAbiType, Default)]
+pub struct UserOpsPerAggregator {
+    pub user_ops: Vec<UserOperation>,
+    pub aggregator: Address,
+    pub signature: Bytes,
+}
+
+#[derive(
+    Clone,
+    Debug,
+    Eq,
+    PartialEq,
+    ethers :: contract :: EthCall,
+    ethers :: contract :: EthDisplay,
+    Default,
+)]
+#[ethcall(
+    name = "handleAggregatedOps",
+    abi = "handleAggregatedOps(((address,uint256,bytes,bytes,uint256,uint256,uint256,uint256,uint256,bytes,bytes)[],address,bytes)[],address)"
+)]
+pub struct HandleAggregatedOpsCall {
+    pub ops_per_aggregator: Vec<UserOpsPerAggregator>,
+    pub beneficiary: Address,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, EthAbiCodec, EthAbiType, Default)]
+pub struct UserOpsPerAggregatorV07 {
+    pub user_ops: Vec<PackedUserOperation>,
+    pub aggregator: Address,
+    pub signature: Bytes,
+}
+
+#[derive(
+    Clone,
+    Debug,
+    Eq,
+    PartialEq,
+    ethers :: contract :: EthCall,
+    ethers :: contract :: EthDisplay,
+    Default,
+)]
+#[ethcall(
+    name = "handleAggregatedOps",
+    abi = "handleAggregatedOps(((address,uint256,bytes,bytes,bytes32,uint256,bytes32,bytes,bytes)[],address,bytes)[],address)"
+)]
+pub struct HandleAggregatedOpsV07Call {
+    pub ops_per_aggregator: Vec<UserOpsPerAggregatorV07>,
+    pub beneficiary: Address,
+}
+
 #[cfg(test)]
 mod test {
     use std::str::FromStr;
 
-    use ethers::types::{Bytes, H160, U256};
+    use ethers::{
+        abi::{encode, Token},
+        prelude::EthEvent,
+        types::{Address, Bytes, Log, H160, H256, U256},
+    };
+
+    use super::{AccountDeployedEvent, EntryPointEvent, PackedUserOperation, UserOperation};
 
-    use super::UserOperation;
+    #[test]
+    fn packed_user_operation_unpack() {
+        let mut account_gas_limits = [0u8; 32];
+        account_gas_limits[15] = 7; // verificationGasLimit
+        account_gas_limits[31] = 9; // callGasLimit
+        let mut gas_fees = [0u8; 32];
+        gas_fees[15] = 1; // maxPriorityFeePerGas
+        gas_fees[31] = 2; // maxFeePerGas
+        let packed = PackedUserOperation {
+            sender: H160::from_str("0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266").unwrap(),
+            nonce: U256::from(3),
+            init_code: Bytes::new(),
+            call_data: Bytes::new(),
+            account_gas_limits: H256::from(account_gas_limits),
+            pre_verification_gas: U256::from(21000),
+            gas_fees: H256::from(gas_fees),
+            paymaster_and_data: Bytes::new(),
+            signature: Bytes::new(),
+        };
+        // sender, nonce, initCode, callData, accountGasLimits, preVerificationGas, gasFees, paymasterAndData
+        let encoded = packed.pack();
+        assert_eq!(encoded.len(), 8 * 32);
+        assert_eq!(&encoded[4 * 32..5 * 32], &account_gas_limits);
+        assert_eq!(&encoded[6 * 32..7 * 32], &gas_fees);
+
+        let uo = UserOperation::from(packed);
+        assert_eq!(uo.verification_gas_limit, U256::from(7));
+        assert_eq!(uo.call_gas_limit, U256::from(9));
+        assert_eq!(uo.max_priority_fee_per_gas, U256::from(1));
+        assert_eq!(uo.max_fee_per_gas, U256::from(2));
+    }
+
+    #[test]
+    fn decode_account_deployed() {
+        let sender = Address::from_low_u64_be(1);
+        let factory = Address::from_low_u64_be(2);
+        let log = Log {
+            topics: vec![
+                AccountDeployedEvent::signature(),
+                H256::from_low_u64_be(42),
+                H256::from(sender),
+            ],
+            data: encode(&[Token::Address(factory), Token::Address(Address::zero())]).into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            EntryPointEvent::decode(log).unwrap(),
+            Some(EntryPointEvent::AccountDeployed(AccountDeployedEvent {
+                user_op_hash: H256::from_low_u64_be(42),
+                sender,
+                factory,
+                paymaster: Address::zero(),
+            }))
+        );
+    }
 
     #[test]
     fn user_operation_hash() {